@@ -1,32 +1,93 @@
-use libcryptsetup_rs::consts::flags::CryptActivate;
+use libcryptsetup_rs::consts::flags::{CryptActivate, CryptDeactivate, CryptWipe};
 use libcryptsetup_rs::{
-    consts::{flags::CryptVolumeKey, vals::EncryptionFormat},
-    CryptInit, LibcryptErr,
+    consts::{
+        flags::CryptVolumeKey,
+        vals::{CryptWipePattern, EncryptionFormat},
+    },
+    CryptInit, CryptParamsLuks2, CryptParamsLuks2Ref, LibcryptErr,
 };
 use nix::libc::{self};
 use regex::Regex;
 use std::fs::{self, remove_file, File};
 use std::io::{self, Write};
+use std::os::raw::{c_int, c_void};
 use std::path::Path;
 // Do not use other Result functions!
 use core::result::Result;
 use std::sync::Mutex;
 
-use crate::funcs::{prompt, run_command, run_shell_command};
+use crate::funcs::{find_option, prompt, run_command, run_shell_command};
 
 mod funcs;
 
 static IS_SSD: Mutex<bool> = Mutex::new(false);
 static IS_NVME: Mutex<bool> = Mutex::new(false);
+static IS_ROTATIONAL: Mutex<bool> = Mutex::new(false);
 static WRONG_OPTION: Mutex<bool> = Mutex::new(false);
 static WRONG_DISK: Mutex<bool> = Mutex::new(false);
 static SAID_NO: Mutex<bool> = Mutex::new(false);
 static WRONG_PASSWORD: Mutex<bool> = Mutex::new(false);
+static INTEGRITY: Mutex<Integrity> = Mutex::new(Integrity::None);
+static LVM: Mutex<bool> = Mutex::new(false);
+static BIOS: Mutex<bool> = Mutex::new(false);
+
+// Rough estimate in bits; 60 is about 13 random lowercase letters or 5 random diceware words.
+const MIN_PASSWORD_ENTROPY: f64 = 60.0;
+
+// ASCII symbols that sit on different keys, or need other modifiers, on most non-US layouts.
+const LAYOUT_SENSITIVE_SYMBOLS: &str = "`~!@#$%^&*()-_=+[]{}\\|;:'\",.<>/?";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Integrity {
+    None,
+    HmacSha256,
+    Aead,
+}
+
+impl Integrity {
+    // Written to selected_disk.cfg, so post_chroot knows which modules the initramfs needs.
+    fn name(self) -> &'static str {
+        match self {
+            Integrity::None => "none",
+            Integrity::HmacSha256 => "hmac-sha256",
+            Integrity::Aead => "aead",
+        }
+    }
+
+    // libcryptsetup's integrity name, cipher, and key size in bits (cipher key + integrity key).
+    fn format_params(self) -> (Option<&'static str>, (&'static str, &'static str), usize) {
+        match self {
+            Integrity::None => (None, ("aes", "xts-plain"), 512),
+            Integrity::HmacSha256 => (Some("hmac(sha256)"), ("aes", "xts-plain64"), 512 + 256),
+            Integrity::Aead => (Some("aead"), ("aes", "gcm-random"), 256),
+        }
+    }
+
+    fn cost(self) -> &'static str {
+        match self {
+            Integrity::None => "none",
+            Integrity::HmacSha256 => {
+                "~1% of the partition for tags, writes are journaled (roughly half the write speed), \
+                and the whole partition is wiped once before use"
+            }
+            Integrity::Aead => {
+                "~1% of the partition for tags, writes are journaled (roughly half the write speed), \
+                and the whole partition is wiped once before use; AES-GCM is less tested than AES-XTS"
+            }
+        }
+    }
+}
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let _ = fs::create_dir("/root/arch-flux");
     let mut selected_disk = "/dev/null".to_string();
 
+    // The live environment only has /sys/firmware/efi if it was booted through UEFI.
+    *BIOS.lock().unwrap() = !Path::new("/sys/firmware/efi").exists();
+    if *BIOS.lock().unwrap() {
+        println!("Booted in legacy BIOS mode, GRUB will be installed to the disk's BIOS boot partition.\n");
+    }
+
     loop {
         disk_selection(&mut selected_disk);
 
@@ -35,7 +96,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     }
 
-    disk_editing(&mut selected_disk)?;
+    disk_editing(&selected_disk)?;
 
     let file_path = "/root/arch-flux/selected_disk.cfg";
     remove_file(file_path).ok();
@@ -46,6 +107,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let disk_type = if *IS_NVME.lock().unwrap() {
         "NVME"
+    } else if *IS_ROTATIONAL.lock().unwrap() {
+        "HDD"
     } else if *IS_SSD.lock().unwrap() {
         "SSD"
     } else {
@@ -54,6 +117,15 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let result = format!("{}\n", disk_type);
     config.write_all(result.as_bytes())?;
 
+    let integrity = format!("integrity={}\n", INTEGRITY.lock().unwrap().name());
+    config.write_all(integrity.as_bytes())?;
+
+    let storage = if *LVM.lock().unwrap() { "lvm" } else { "partitions" };
+    config.write_all(format!("storage={}\n", storage).as_bytes())?;
+
+    let firmware = if *BIOS.lock().unwrap() { "bios" } else { "uefi" };
+    config.write_all(format!("firmware={}\n", firmware).as_bytes())?;
+
     Ok(())
 }
 
@@ -104,17 +176,82 @@ fn disk_selection(selected_disk: &mut String) {
         }
     }
 
+    let rotational = is_rotational(selected_disk);
+    *IS_ROTATIONAL.lock().unwrap() = rotational;
+
+    let integrity = integrity_selection();
+    *INTEGRITY.lock().unwrap() = integrity;
+
+    let lvm = lvm_selection();
+    *LVM.lock().unwrap() = lvm;
+
+    println!("\nDisk: {} (all data on it will be erased)", selected_disk);
+    if rotational {
+        println!("Disk type: rotational");
+    } else {
+        println!("Disk type: solid state (TRIM and no-workqueue flags are stored in the LUKS2 header)");
+    }
+    println!("LUKS2 integrity: {}", integrity.name());
+    println!("Integrity cost: {}", integrity.cost());
+    if lvm {
+        println!("Storage layout: LVM on LUKS2 (root, home and swap volumes)\n");
+    } else {
+        println!("Storage layout: partitions (swap is re-encrypted with a random key every boot)\n");
+    }
+
     let input = prompt("Are you sure [y/n]: ");
 
     match input.to_lowercase().as_ref() {
-        "y" if input.len() == 1 => return,
+        "y" if input.len() == 1 => {}
         "n" if input.len() == 1 => {
             *SAID_NO.lock().unwrap() = true;
-            return;
         }
         _ => {
             *WRONG_OPTION.lock().unwrap() = true;
-            return;
+        }
+    }
+}
+
+// /dev/sdX is also used by HDDs and USB sticks, so ask the kernel instead of going by the name.
+fn is_rotational(disk: &str) -> bool {
+    let name = disk.trim_start_matches("/dev/");
+    fs::read_to_string(format!("/sys/block/{}/queue/rotational", name))
+        .map(|rotational| rotational.trim() == "1")
+        .unwrap_or(false)
+}
+
+fn integrity_selection() -> Integrity {
+    loop {
+        let input = prompt(
+            "Authenticated encryption (dm-integrity) detects tampering and bit-rot, at a performance cost.\n\
+            0: None (default)\n\
+            1: hmac-sha256 (AES-XTS + HMAC-SHA256)\n\
+            2: aead (AES-GCM)\n\
+            Select an integrity mode, then press ENTER: ",
+        );
+
+        match input.as_str() {
+            "" | "0" => return Integrity::None,
+            "1" => return Integrity::HmacSha256,
+            "2" => return Integrity::Aead,
+            _ => println!("\nNOTICE: Please enter '0', '1' or '2'.\n"),
+        }
+    }
+}
+
+fn lvm_selection() -> bool {
+    loop {
+        let input = prompt(
+            "LVM on LUKS2 puts the root, home and swap volumes inside the encrypted container.\n\
+            0: Partitions (default)\n\
+            1: LVM\n\
+            Select a storage layout, then press ENTER: ",
+        );
+
+        match input.as_str() {
+            "" | "0" => return false,
+            "1" => return true,
+            _ => println!("\nNOTICE: Please enter '0' or '1'.\n"),
         }
     }
 }
@@ -144,19 +281,19 @@ fn wipe_disk(device_path: &str) -> io::Result<()> {
     let whole_disk: String = format!("wipefs -af {}*", device_path);
     run_shell_command(&whole_disk)?;
     // Remove disk's GPT & MBR data structures
-    run_command("sgdisk", &["-Z", &device_path])?;
+    run_command("sgdisk", &["-Z", device_path])?;
     Ok(())
 }
 
 fn create_partitions(device_path: &str) -> Result<(), Box<dyn std::error::Error>> {
     unsafe {
         // These need to be separate variables in order to do the math equation.
-        let total_pages: i64 = libc::sysconf(libc::_SC_PHYS_PAGES).try_into().unwrap();
-        let page_size: i64 = libc::sysconf(libc::_SC_PAGE_SIZE).try_into().unwrap();
+        let total_pages: i64 = libc::sysconf(libc::_SC_PHYS_PAGES);
+        let page_size: i64 = libc::sysconf(libc::_SC_PAGE_SIZE);
         let total_ram: i64 = (total_pages * page_size) / (1024 * 1024);
 
         // Create GPT disk 2048 alignment
-        run_command("sgdisk", &["-a", "2048", "-o", &device_path])?;
+        run_command("sgdisk", &["-a", "2048", "-o", device_path])?;
 
         run_command(
             "sgdisk",
@@ -164,19 +301,49 @@ fn create_partitions(device_path: &str) -> Result<(), Box<dyn std::error::Error>
                 "-n 1::+1024M",
                 "--typecode=1:ef00",
                 "--change-name=1:'BOOTEFI'",
-                &device_path,
+                device_path,
             ],
         )?;
 
-        let ram = format!("-n 2::+{}", total_ram.to_string());
-        run_command("sgdisk", &[&ram, "--typecode=2:8200", &device_path])?;
+        // GRUB for i386-pc embeds its core image in a BIOS boot partition, in the gap before partition 1.
+        // Partition 1 stays an unencrypted FAT32 /boot, which GRUB reads without cryptodisk support.
+        if *BIOS.lock().unwrap() {
+            run_command(
+                "sgdisk",
+                &[
+                    "-a",
+                    "1",
+                    "-n",
+                    "4:34:2047",
+                    "--typecode=4:ef02",
+                    "--change-name=4:BIOSBOOT",
+                    device_path,
+                ],
+            )?;
+        }
+
+        // With LVM the swap volume lives inside the LUKS2 container, so partition 2 is left out.
+        if !*LVM.lock().unwrap() {
+            let ram = format!("-n 2::+{}", total_ram);
+            run_command("sgdisk", &[&ram, "--typecode=2:8200", device_path])?;
+        }
+
+        // dm-integrity uses 4 KiB sectors and needs the partition to be a multiple of them,
+        // so end on the last 1 MiB boundary instead of the disk's last usable sector.
+        let root_part = if *INTEGRITY.lock().unwrap() != Integrity::None {
+            let output = run_command("sgdisk", &["-E", device_path])?;
+            let last_sector: u64 = String::from_utf8_lossy(&output.stdout).trim().parse()?;
+            format!("-n 3::{}", ((last_sector + 1) / 2048) * 2048 - 1)
+        } else {
+            "-n 3::-0".to_string()
+        };
         run_command(
             "sgdisk",
-            &["-n 3::-0", "--typecode=3:8300", "--change-name=3:'ROOT'", &device_path],
+            &[&root_part, "--typecode=3:8300", "--change-name=3:'ROOT'", device_path],
         )?;
 
         // Inform kernel of partition changes
-        run_command("partprobe", &[&device_path])?;
+        run_command("partprobe", &[device_path])?;
     }
 
     Ok(())
@@ -186,6 +353,14 @@ fn create_luks2_container(selected_disk: &str) -> Result<(), LibcryptErr> {
     *WRONG_PASSWORD.lock().unwrap() = false;
 
     let password = funcs::prompt_u8("\nEnter a new password for the LUKS2 container: ");
+
+    if password.is_empty() {
+        *WRONG_PASSWORD.lock().unwrap() = true;
+        return Err(LibcryptErr::Other(
+            "The password cannot be empty, try again.".to_string(),
+        ));
+    }
+
     let password_check = funcs::prompt_u8("Please repeat your new password: ");
 
     if password != password_check {
@@ -193,12 +368,17 @@ fn create_luks2_container(selected_disk: &str) -> Result<(), LibcryptErr> {
         return Err(LibcryptErr::Other("Passwords do not match, try again.".to_string()));
     }
 
+    if !password_quality_accepted(&password) {
+        *WRONG_PASSWORD.lock().unwrap() = true;
+        return Err(LibcryptErr::Other("Password rejected, try again.".to_string()));
+    }
+
     let mut luks_part = selected_disk.to_string();
 
-    if *IS_NVME.lock().unwrap() == true {
+    if *IS_NVME.lock().unwrap() {
         luks_part.push_str("p3");
-    } else if *IS_SSD.lock().unwrap() == true {
-        luks_part.push_str("3");
+    } else if *IS_SSD.lock().unwrap() {
+        luks_part.push('3');
     }
 
     let luks_part_str: &str = &luks_part;
@@ -206,29 +386,211 @@ fn create_luks2_container(selected_disk: &str) -> Result<(), LibcryptErr> {
     let sd = Path::new(luks_part_str);
     let mut device = CryptInit::init(sd)?;
 
-    device.context_handle().format::<()>(
-        EncryptionFormat::Luks2,
-        ("aes", "xts-plain"),
-        None,
-        libcryptsetup_rs::Either::Right(512 / 8), // 512bit key
-        None,
-    )?;
+    let integrity = *INTEGRITY.lock().unwrap();
+    let (integrity_name, cipher, key_bits) = integrity.format_params();
+
+    if let Some(integrity_name) = integrity_name {
+        let params = CryptParamsLuks2 {
+            pbkdf: None,
+            integrity: Some(integrity_name.to_string()),
+            integrity_params: None,
+            data_alignment: 0,
+            data_device: None,
+            sector_size: 4096,
+            label: None,
+            subsystem: None,
+        };
+        let mut params_ref: CryptParamsLuks2Ref = (&params).try_into()?;
+
+        device.context_handle().format(
+            EncryptionFormat::Luks2,
+            cipher,
+            None,
+            libcryptsetup_rs::Either::Right(key_bits / 8),
+            Some(&mut params_ref),
+        )?;
+    } else {
+        device.context_handle().format::<()>(
+            EncryptionFormat::Luks2,
+            cipher,
+            None,
+            libcryptsetup_rs::Either::Right(key_bits / 8),
+            None,
+        )?;
+    }
 
     device
         .keyslot_handle()
         .add_by_key(None, None, &password, CryptVolumeKey::empty())?;
 
     device.context_handle().load::<()>(None, None)?;
+
+    let mut activate_flags = if *IS_ROTATIONAL.lock().unwrap() {
+        CryptActivate::empty()
+    } else {
+        // Skipping dm-crypt's workqueues lowers latency on SSDs and NVMe drives.
+        CryptActivate::ALLOW_DISCARDS | CryptActivate::NO_READ_WORKQUEUE | CryptActivate::NO_WRITE_WORKQUEUE
+    };
+    // Like the discard option in crypttab, see root_luks_options() in funcs/fstab.rs: dm-integrity can't pass
+    // discards through when dm-crypt provides its tags, and a persistent flag would apply on every activation.
+    if integrity != Integrity::None {
+        activate_flags.remove(CryptActivate::ALLOW_DISCARDS);
+    }
+
+    // Persistent flags live in the LUKS2 header, so the installed system's initramfs activates with them too.
+    if !activate_flags.is_empty() {
+        device
+            .luks2_flag_handle::<CryptActivate>()
+            .persistent_flags_set(activate_flags)?;
+    }
+
+    if integrity != Integrity::None {
+        wipe_integrity_tags(&mut device, &password)?;
+    }
     device.activate_handle().activate_by_passphrase(
         Some("arch"),
         Some(libcryptsetup_rs_sys::CRYPT_ANY_SLOT as u32),
         &password,
-        CryptActivate::empty(),
+        activate_flags,
     )?;
 
     Ok(())
 }
 
+// Character pool size times length, where repeating characters don't count past their second use.
+fn estimate_entropy(password: &str) -> f64 {
+    let mut pool = 0;
+    if password.chars().any(|c| c.is_ascii_lowercase()) {
+        pool += 26;
+    }
+    if password.chars().any(|c| c.is_ascii_uppercase()) {
+        pool += 26;
+    }
+    if password.chars().any(|c| c.is_ascii_digit()) {
+        pool += 10;
+    }
+    if password.chars().any(|c| c.is_ascii_punctuation() || c == ' ') {
+        pool += 33;
+    }
+    if !password.is_ascii() {
+        pool += 100;
+    }
+
+    let mut distinct: Vec<char> = password.chars().collect();
+    distinct.sort_unstable();
+    distinct.dedup();
+
+    let length = password.chars().count().min(distinct.len() * 2);
+    length as f64 * (pool as f64).log2()
+}
+
+// The symbols that move for the layouts where that's known, the whole set for the others.
+fn moved_symbols(layout: &str) -> &'static str {
+    match layout {
+        // The us-* variants only add dead keys and AltGr symbols.
+        _ if layout == "us" || layout.starts_with("us-") => "",
+        "uk" => "\"@#~\\|",
+        // German keeps !, $, %, the comma and the period on the same keys.
+        "de" => "`~@#^&*()-_=+[]{}\\|;:'\"<>/?",
+        _ => LAYOUT_SENSITIVE_SYMBOLS,
+    }
+}
+
+// Characters that come out differently if the boot prompt still uses the US keymap.
+fn keymap_sensitive_chars(password: &str, layout: &str) -> Vec<char> {
+    let letters = match layout {
+        // QWERTZ layouts swap Y and Z.
+        "cz" | "de" | "hu" | "sg" => "yzYZ",
+        // AZERTY also needs Shift for digits.
+        "fr" | "cf" => "aqwzmAQWZM0123456789",
+        _ => "",
+    };
+
+    let mut chars: Vec<char> = password
+        .chars()
+        .filter(|c| !c.is_ascii() || letters.contains(*c) || moved_symbols(layout).contains(*c))
+        .collect();
+    chars.sort_unstable();
+    chars.dedup();
+    chars
+}
+
+fn password_quality_accepted(password: &[u8]) -> bool {
+    let password = String::from_utf8_lossy(password);
+
+    let entropy = estimate_entropy(&password);
+    if entropy < MIN_PASSWORD_ENTROPY {
+        println!(
+            "\nWARNING: This password is weak (~{:.0} bits, recommended: at least {:.0} bits).",
+            entropy, MIN_PASSWORD_ENTROPY
+        );
+        if prompt("Use it anyway [y/n]: ").to_lowercase() != "y" {
+            return false;
+        }
+    }
+
+    // disk_format usually runs before the installer, so fall back to asking for the layout.
+    let layout = find_option("keyboard_layout").unwrap_or_else(|_| {
+        let input = prompt("\nKeyboard layout you will use on the installed system (default: us): ");
+        if input.is_empty() {
+            "us".to_string()
+        } else {
+            input.to_lowercase()
+        }
+    });
+
+    let sensitive_chars = keymap_sensitive_chars(&password, &layout);
+    if !sensitive_chars.is_empty() {
+        println!(
+            "\nWARNING: These characters are typed differently on the '{}' and 'us' keyboard layouts: {}",
+            layout,
+            sensitive_chars.iter().collect::<String>()
+        );
+        println!("The password prompt at boot may use the 'us' layout before your keymap gets loaded.");
+        if prompt("Use it anyway [y/n]: ").to_lowercase() != "y" {
+            return false;
+        }
+    }
+
+    true
+}
+
+unsafe extern "C" fn wipe_progress(size: u64, offset: u64, _usrptr: *mut c_void) -> c_int {
+    if let Some(percent) = (offset * 100).checked_div(size) {
+        print!("\rInitializing integrity tags: {}%", percent);
+        let _ = io::stdout().flush();
+    }
+    0
+}
+
+// Unwritten sectors have no valid integrity tags, and reading them fails with I/O errors;
+// zeroing the whole mapping once computes a tag for every sector.
+fn wipe_integrity_tags(device: &mut libcryptsetup_rs::CryptDevice, password: &[u8]) -> Result<(), LibcryptErr> {
+    // The journal is only needed for crash consistency, skip it to halve the wipe time.
+    device.activate_handle().activate_by_passphrase(
+        Some("arch"),
+        Some(libcryptsetup_rs_sys::CRYPT_ANY_SLOT as u32),
+        password,
+        CryptActivate::NO_JOURNAL | CryptActivate::PRIVATE,
+    )?;
+
+    device.wipe_handle().wipe::<()>(
+        Path::new("/dev/mapper/arch"),
+        CryptWipePattern::Zero,
+        0,
+        0,
+        1024 * 1024,
+        CryptWipe::empty(),
+        Some(wipe_progress),
+        None,
+    )?;
+    println!();
+
+    device.activate_handle().deactivate("arch", CryptDeactivate::empty())?;
+
+    Ok(())
+}
+
 fn disk_editing(selected_disk: &str) -> Result<(), Box<dyn std::error::Error>> {
     wipe_disk(selected_disk)?;
     create_partitions(selected_disk)?;
@@ -0,0 +1,474 @@
+use super::{config_write, run_command};
+use anyhow::{bail, Context};
+use std::fs;
+use std::io::Read;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+pub const GRUB_EFI_BINARY: &str = "/boot/EFI/GRUB/grubx64.efi";
+pub const SYSTEMD_BOOT_EFI_BINARY: &str = "/boot/EFI/systemd/systemd-bootx64.efi";
+
+// The same binaries as the firmware sees them on the ESP, for efibootmgr.
+pub const GRUB_LOADER: &str = r"\EFI\GRUB\grubx64.efi";
+pub const SYSTEMD_BOOT_LOADER: &str = r"\EFI\systemd\systemd-bootx64.efi";
+
+pub const FALLBACK_EFI_BINARY: &str = "/boot/EFI/BOOT/BOOTX64.EFI";
+
+pub const UKI_DIR: &str = "/boot/EFI/Linux";
+
+pub const GRUB_BIOS_CORE_IMAGE: &str = "/boot/grub/i386-pc/core.img";
+
+pub enum GrubTarget<'a> {
+    Efi { secure_boot: bool },
+    // Legacy BIOS boots GRUB from the disk's MBR and BIOS boot partition.
+    Bios { disk: &'a str },
+}
+
+// Under Secure Boot, GRUB must not ask shim to verify what it loads, since there's no shim.
+pub fn install_grub(cmdline: &[String], target: GrubTarget, default_kernel: &str) -> anyhow::Result<()> {
+    let args = match target {
+        GrubTarget::Efi { secure_boot } => {
+            let mut args = vec!["--target=x86_64-efi", "--efi-directory=/boot", "--bootloader-id=GRUB"];
+            if secure_boot {
+                args.extend(["--modules=tpm", "--disable-shim-lock"]);
+            }
+            args
+        }
+        GrubTarget::Bios { disk } => vec!["--target=i386-pc", disk],
+    };
+    run_command("grub-install", &args).with_context(|| "Failed to install GRUB")?;
+
+    let grub_path = "/etc/default/grub";
+    config_write(&format!("\"{}\"", cmdline.join(" ")), "GRUB_CMDLINE_LINUX=", grub_path)
+        .with_context(|| "Failed to write GRUB_CMDLINE_LINUX")?;
+    // Everything is in GRUB_CMDLINE_LINUX, so recovery entries don't lose quiet or splash to a second list.
+    config_write("\"\"", "GRUB_CMDLINE_LINUX_DEFAULT=", grub_path)?;
+    // Otherwise grub-mkconfig puts whichever kernel has the highest version first.
+    config_write(
+        &format!("\"/boot/vmlinuz-{}\"", default_kernel),
+        "GRUB_TOP_LEVEL=",
+        grub_path,
+    )?;
+    // mkinitcpio's microcode hook already puts the microcode into the initramfs, so don't load it twice.
+    config_write("\"\"", "GRUB_EARLY_INITRD_LINUX_STOCK=", grub_path)?;
+    // os-prober is installed to find other operating systems, but GRUB ignores it by default.
+    config_write("false", "GRUB_DISABLE_OS_PROBER=", grub_path)?;
+
+    run_command("grub-mkconfig", &["-o", "/boot/grub/grub.cfg"]).with_context(|| "Failed to generate grub.cfg")?;
+
+    match target {
+        GrubTarget::Efi { .. } => verify_grub(),
+        GrubTarget::Bios { disk } => verify_grub_bios(disk),
+    }
+}
+
+// GRUB's boot.img in the MBR contains the string "GRUB", which it prints on errors.
+pub fn verify_grub_bios(disk: &str) -> anyhow::Result<()> {
+    if !Path::new(GRUB_BIOS_CORE_IMAGE).is_file() {
+        bail!("{} is missing after grub-install", GRUB_BIOS_CORE_IMAGE);
+    }
+
+    let mut mbr = [0u8; 512];
+    fs::File::open(disk)
+        .and_then(|mut file| file.read_exact(&mut mbr))
+        .with_context(|| format!("Failed to read the MBR of {}", disk))?;
+    if !mbr.windows(4).any(|window| window == b"GRUB") {
+        bail!("The MBR of {} has no GRUB boot code", disk);
+    }
+
+    println!("Verified GRUB's core image and boot code on {}", disk);
+    Ok(())
+}
+
+// grub-install exits successfully even if efibootmgr couldn't write the boot entry, e.g. without efivarfs.
+pub fn verify_grub() -> anyhow::Result<()> {
+    if !Path::new(GRUB_EFI_BINARY).is_file() {
+        bail!("{} is missing after grub-install", GRUB_EFI_BINARY);
+    }
+
+    let output = run_command("efibootmgr", &[]).with_context(|| "Failed to list the EFI boot entries")?;
+    if !String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .any(|line| line.contains(" GRUB"))
+    {
+        bail!("The firmware has no GRUB boot entry");
+    }
+
+    println!("Verified the GRUB EFI binary and boot entry");
+    Ok(())
+}
+
+// Read by systemd-boot-entries, and embedded into UKIs by mkinitcpio.
+fn write_kernel_cmdline(cmdline: &[String]) -> anyhow::Result<()> {
+    fs::create_dir_all("/etc/kernel")?;
+    fs::write("/etc/kernel/cmdline", format!("{}\n", cmdline.join(" ")))
+        .with_context(|| "Failed to write /etc/kernel/cmdline")?;
+    Ok(())
+}
+
+// snapper-boot-entries bases its snapshot entries on the default entry, or on the default UKI.
+fn write_loader_conf(default_entry: &str) -> anyhow::Result<()> {
+    let contents = format!("default {}\ntimeout 3\nconsole-mode max\neditor no\n", default_entry);
+    fs::write("/boot/loader/loader.conf", contents).with_context(|| "Failed to write loader.conf")?;
+    Ok(())
+}
+
+// UKIs in EFI/Linux are discovered by systemd-boot itself, so they don't need entries.
+// Otherwise entries are written by systemd-boot-entries, which a pacman hook reruns whenever a kernel changes.
+pub fn install_systemd_boot(cmdline: &[String], ukis: bool, default_kernel: &str) -> anyhow::Result<()> {
+    run_command("bootctl", &["install", "--esp-path=/boot"]).with_context(|| "Failed to install systemd-boot")?;
+
+    if ukis {
+        write_loader_conf(&format!("arch-{}.efi", default_kernel))?;
+        return verify_systemd_boot();
+    }
+    write_loader_conf(&format!("arch-{}.conf", default_kernel))?;
+    write_kernel_cmdline(cmdline)?;
+
+    let files = [
+        "/usr/local/bin/systemd-boot-entries",
+        "/etc/pacman.d/hooks/95-systemd-boot-entries.hook",
+    ];
+    for file in files {
+        fs::copy(format!("/root/arch-flux/files{}", file), file).with_context(|| format!("Failed to copy {}", file))?;
+    }
+    fs::set_permissions("/usr/local/bin/systemd-boot-entries", fs::Permissions::from_mode(0o755))?;
+
+    run_command("/usr/local/bin/systemd-boot-entries", &[]).with_context(|| "Failed to write the boot entries")?;
+
+    verify_systemd_boot()
+}
+
+pub fn verify_systemd_boot() -> anyhow::Result<()> {
+    if !Path::new(SYSTEMD_BOOT_EFI_BINARY).is_file() {
+        bail!("{} is missing after bootctl install", SYSTEMD_BOOT_EFI_BINARY);
+    }
+
+    let output = run_command("efibootmgr", &[]).with_context(|| "Failed to list the EFI boot entries")?;
+    if !String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .any(|line| line.contains("Linux Boot Manager"))
+    {
+        bail!("The firmware has no systemd-boot entry");
+    }
+
+    println!("Verified the systemd-boot EFI binary and boot entry");
+    Ok(())
+}
+
+// Switches every kernel's mkinitcpio preset to building a UKI, which embeds the command line, initramfs,
+// microcode and os-release. Fallback UKIs are around as large as the kernel and initramfs combined,
+// so they're optional to save space on the 1 GiB ESP. Returns the kernels that have a preset.
+pub fn configure_ukis(cmdline: &[String], fallback: bool) -> anyhow::Result<Vec<String>> {
+    write_kernel_cmdline(cmdline)?;
+    fs::create_dir_all(UKI_DIR)?;
+
+    let mut kernels = Vec::new();
+    for entry in fs::read_dir("/etc/mkinitcpio.d")? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|extension| extension != "preset") {
+            continue;
+        }
+        let kernel = path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+
+        let mut preset = format!("ALL_kver=\"/boot/vmlinuz-{}\"\n", kernel);
+        if fallback {
+            preset.push_str(&format!(
+                "PRESETS=('default' 'fallback')\n\
+                default_uki=\"{dir}/arch-{kernel}.efi\"\n\
+                fallback_uki=\"{dir}/arch-{kernel}-fallback.efi\"\n\
+                fallback_options=\"-S autodetect\"\n",
+                dir = UKI_DIR,
+                kernel = kernel
+            ));
+        } else {
+            preset.push_str(&format!(
+                "PRESETS=('default')\ndefault_uki=\"{}/arch-{}.efi\"\n",
+                UKI_DIR, kernel
+            ));
+        }
+        fs::write(&path, preset).with_context(|| format!("Failed to write {}", path.display()))?;
+
+        // The separate initramfs images are unused now, and only take up ESP space.
+        for image in [
+            format!("/boot/initramfs-{}.img", kernel),
+            format!("/boot/initramfs-{}-fallback.img", kernel),
+        ] {
+            let _ = fs::remove_file(image);
+        }
+        kernels.push(kernel);
+    }
+
+    if !kernels.is_empty() {
+        run_command("mkinitcpio", &["-P"]).with_context(|| "Failed to build the UKIs")?;
+    }
+    for kernel in &kernels {
+        let uki = format!("{}/arch-{}.efi", UKI_DIR, kernel);
+        if !Path::new(&uki).is_file() {
+            bail!("{} is missing after mkinitcpio -P", uki);
+        }
+    }
+
+    Ok(kernels)
+}
+
+// A firmware boot entry, e.g. "Boot0003* GRUB	HD(1,GPT,<partuuid>,0x800,0x200000)/\EFI\GRUB\grubx64.efi".
+pub struct BootEntry {
+    pub number: String,
+    pub label: String,
+    pub device_path: String,
+}
+
+impl BootEntry {
+    // Entries for network boot or the firmware's own apps aren't on a partition, so they have none.
+    pub fn partuuid(&self) -> Option<String> {
+        let start = self.device_path.find("HD(")? + 3;
+        let end = self.device_path[start..].find(')')? + start;
+        self.device_path[start..end].split(',').nth(2).map(str::to_lowercase)
+    }
+
+    // efibootmgr 18 prints the loader after the device path, older versions wrap it in File(...).
+    // Entries without a file, like PXE, end in another device path node instead.
+    pub fn loader(&self) -> Option<&str> {
+        let (_, path) = self.device_path.rsplit_once(")/")?;
+        let path = path
+            .strip_prefix("File(")
+            .map_or(path, |path| path.trim_end_matches(')'));
+        path.starts_with('\\').then_some(path)
+    }
+}
+
+pub fn boot_entries() -> anyhow::Result<Vec<BootEntry>> {
+    let output = run_command("efibootmgr", &[]).with_context(|| "Failed to list the EFI boot entries")?;
+    Ok(parse_boot_entries(&String::from_utf8_lossy(&output.stdout)))
+}
+
+fn parse_boot_entries(output: &str) -> Vec<BootEntry> {
+    let mut entries = Vec::new();
+    for line in output.lines() {
+        let Some((number, rest)) = line.strip_prefix("Boot").and_then(|line| line.split_once(' ')) else {
+            continue;
+        };
+        // Skips BootCurrent, BootNext and BootOrder.
+        if number.len() < 4 || !number[..4].chars().all(|c| c.is_ascii_hexdigit()) {
+            continue;
+        }
+        let (label, device_path) = rest.trim_start().split_once('\t').unwrap_or((rest.trim_start(), ""));
+
+        entries.push(BootEntry {
+            number: number[..4].to_string(),
+            label: label.to_string(),
+            device_path: device_path.trim().to_string(),
+        });
+    }
+
+    entries
+}
+
+fn delete_boot_entry(entry: &BootEntry) -> anyhow::Result<()> {
+    run_command(
+        "efibootmgr",
+        &["--quiet", "--bootnum", &entry.number, "--delete-bootnum"],
+    )
+    .with_context(|| format!("Failed to delete the boot entry Boot{}", entry.number))?;
+    Ok(())
+}
+
+// Deletes firmware boot entries with this exact label, so reruns don't pile up duplicates.
+pub fn remove_boot_entries(label: &str) -> anyhow::Result<()> {
+    for entry in boot_entries()?.iter().filter(|entry| entry.label == label) {
+        delete_boot_entry(entry)?;
+    }
+    Ok(())
+}
+
+// Entries that GRUB, systemd-boot or install_uki_boot_entries() made during a previous install.
+fn is_our_entry(entry: &BootEntry) -> bool {
+    entry.label == "GRUB" || entry.label == "Linux Boot Manager" || entry.label.starts_with("Arch Linux (")
+}
+
+// Repartitioning gives the ESP a new PARTUUID, which leaves the previous install's entries pointing nowhere.
+// Entries whose partition still exists are kept, since they may belong to another install on another disk.
+pub fn remove_stale_boot_entries() -> anyhow::Result<()> {
+    for entry in boot_entries()?.iter().filter(|entry| is_our_entry(entry)) {
+        let Some(partuuid) = entry.partuuid() else {
+            continue;
+        };
+        if Path::new(&format!("/dev/disk/by-partuuid/{}", partuuid)).exists() {
+            continue;
+        }
+
+        println!(
+            "Removing the stale boot entry Boot{} {} on the missing partition {}",
+            entry.number, entry.label, partuuid
+        );
+        delete_boot_entry(entry)?;
+    }
+    Ok(())
+}
+
+// e.g. \EFI\GRUB\grubx64.efi to /boot/EFI/GRUB/grubx64.efi.
+fn esp_path(loader: &str) -> String {
+    format!("/boot{}", loader.replace('\\', "/"))
+}
+
+// The firmware boots EFI/BOOT/BOOTX64.EFI from removable media, and from any disk once its NVRAM entries are lost.
+// efi-fallback copies the loader there again whenever a kernel changes, since that rebuilds the UKIs.
+pub fn install_efi_fallback(loader: &str) -> anyhow::Result<()> {
+    fs::write("/etc/efi-fallback", format!("{}\n", esp_path(loader)))
+        .with_context(|| "Failed to write /etc/efi-fallback")?;
+
+    let files = [
+        "/usr/local/bin/efi-fallback",
+        "/etc/pacman.d/hooks/96-efi-fallback.hook",
+    ];
+    for file in files {
+        fs::copy(format!("/root/arch-flux/files{}", file), file).with_context(|| format!("Failed to copy {}", file))?;
+    }
+    fs::set_permissions("/usr/local/bin/efi-fallback", fs::Permissions::from_mode(0o755))?;
+
+    run_command("/usr/local/bin/efi-fallback", &[]).with_context(|| "Failed to copy the loader to EFI/BOOT")?;
+    if !Path::new(FALLBACK_EFI_BINARY).is_file() {
+        bail!("{} is missing after efi-fallback", FALLBACK_EFI_BINARY);
+    }
+
+    println!("Installed {} as {}", loader, FALLBACK_EFI_BINARY);
+    Ok(())
+}
+
+// Puts the entries for these loaders first, in this order, followed by the rest of the existing BootOrder.
+// Each loader needs an entry on our ESP's PARTUUID whose file exists, otherwise the firmware can't boot it.
+pub fn set_boot_order(esp_partuuid: &str, loaders: &[String]) -> anyhow::Result<()> {
+    let esp_partuuid = esp_partuuid.to_lowercase();
+    let entries = boot_entries()?;
+
+    let mut order = Vec::new();
+    for loader in loaders {
+        let Some(entry) = entries.iter().find(|entry| {
+            entry.partuuid().as_deref() == Some(esp_partuuid.as_str())
+                && entry.loader().is_some_and(|path| path.eq_ignore_ascii_case(loader))
+        }) else {
+            bail!(
+                "The firmware has no boot entry for {} on the ESP (PARTUUID {})",
+                loader,
+                esp_partuuid
+            );
+        };
+        if !Path::new(&esp_path(loader)).is_file() {
+            bail!(
+                "Boot{} {} points to {}, which is missing",
+                entry.number,
+                entry.label,
+                loader
+            );
+        }
+        order.push(entry.number.clone());
+    }
+
+    let output = run_command("efibootmgr", &[]).with_context(|| "Failed to list the EFI boot entries")?;
+    let current_order = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(|line| line.strip_prefix("BootOrder: ").map(str::to_string))
+        .unwrap_or_default();
+    for number in current_order.split(',').map(str::trim) {
+        if !number.is_empty() && !order.iter().any(|ours| ours == number) {
+            order.push(number.to_string());
+        }
+    }
+
+    run_command("efibootmgr", &["--quiet", "--bootorder", &order.join(",")])
+        .with_context(|| "Failed to set the EFI BootOrder")?;
+
+    println!("Set the EFI BootOrder to {}", order.join(","));
+    Ok(())
+}
+
+pub fn uki_loader(kernel: &str) -> String {
+    format!(r"\EFI\Linux\arch-{}.efi", kernel)
+}
+
+// Lets the firmware boot the UKIs directly, without a bootloader in between.
+// New entries go to the front of BootOrder, so the first kernel in boot_order is created last.
+pub fn install_uki_boot_entries(disk: &str, boot_order: &[&str]) -> anyhow::Result<()> {
+    for kernel in boot_order.iter().rev() {
+        let label = format!("Arch Linux ({})", kernel);
+        remove_boot_entries(&label)?;
+
+        run_command(
+            "efibootmgr",
+            &[
+                "--quiet",
+                "--create",
+                "--disk",
+                disk,
+                "--part",
+                "1",
+                "--label",
+                &label,
+                "--loader",
+                &uki_loader(kernel),
+            ],
+        )
+        .with_context(|| format!("Failed to create the boot entry for {}", kernel))?;
+        println!("Created the boot entry {}", label);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EFIBOOTMGR_18: &str = "BootCurrent: 0001
+Timeout: 1 seconds
+BootOrder: 0001,0003,0000
+Boot0000* UEFI: PXE IPv4 Intel(R) Ethernet\tPciRoot(0x0)/Pci(0x1f,0x6)/MAC(001122334455,0)/IPv4(0.0.0.0,0,DHCP)
+Boot0001* GRUB\tHD(1,GPT,9E3C1F2A-5B7D-4E8F-A1B2-C3D4E5F60718,0x800,0x200000)/\\EFI\\GRUB\\grubx64.efi
+Boot0003* Arch Linux (linux-lts)\tHD(1,GPT,9e3c1f2a-5b7d-4e8f-a1b2-c3d4e5f60718,0x800,0x200000)/\
+    \\EFI\\Linux\\arch-linux-lts.efi
+";
+
+    // efibootmgr before version 18 wraps the loader in File(...).
+    const EFIBOOTMGR_17: &str = "BootOrder: 0002
+Boot0002* Linux Boot Manager\tHD(1,GPT,0c2d4e6f-8a1b-4c3d-9e5f-7a8b9c0d1e2f,0x800,0x200000)/\
+    File(\\EFI\\systemd\\systemd-bootx64.efi)
+";
+
+    #[test]
+    fn skips_boot_order_lines() {
+        let entries = parse_boot_entries(EFIBOOTMGR_18);
+        let numbers: Vec<&str> = entries.iter().map(|entry| entry.number.as_str()).collect();
+        assert_eq!(numbers, ["0000", "0001", "0003"]);
+        assert_eq!(entries[2].label, "Arch Linux (linux-lts)");
+    }
+
+    #[test]
+    fn parses_efibootmgr_18() {
+        let entries = parse_boot_entries(EFIBOOTMGR_18);
+        assert_eq!(
+            entries[1].partuuid().as_deref(),
+            Some("9e3c1f2a-5b7d-4e8f-a1b2-c3d4e5f60718")
+        );
+        assert_eq!(entries[1].loader(), Some(GRUB_LOADER));
+        assert_eq!(entries[2].loader(), Some(uki_loader("linux-lts").as_str()));
+    }
+
+    #[test]
+    fn parses_file_paths_of_older_versions() {
+        let entries = parse_boot_entries(EFIBOOTMGR_17);
+        assert_eq!(entries[0].label, "Linux Boot Manager");
+        assert_eq!(
+            entries[0].partuuid().as_deref(),
+            Some("0c2d4e6f-8a1b-4c3d-9e5f-7a8b9c0d1e2f")
+        );
+        assert_eq!(entries[0].loader(), Some(SYSTEMD_BOOT_LOADER));
+    }
+
+    #[test]
+    fn entries_off_disk_have_no_partuuid_or_loader() {
+        let entries = parse_boot_entries(EFIBOOTMGR_18);
+        assert_eq!(entries[0].partuuid(), None);
+        assert_eq!(entries[0].loader(), None);
+    }
+}
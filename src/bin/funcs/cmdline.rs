@@ -0,0 +1,222 @@
+use super::filesystem::{Filesystem, Storage};
+use super::fstab::{blkid_value, root_luks_options};
+use super::layout::{root_subvolume, Subvolume};
+use super::{fetch_disk_type, find_disk_option, find_option, partition_path};
+use anyhow::bail;
+
+// These may be given once per LUKS2 container, so they conflict per UUID instead of per key.
+const PER_UUID_KEYS: [&str; 2] = ["rd.luks.name", "rd.luks.options"];
+
+struct Param {
+    text: String,
+    source: &'static str,
+}
+
+impl Param {
+    // "root" for root=/dev/mapper/arch, "rd.luks.name=<uuid>" for rd.luks.name=<uuid>=arch, "quiet" for quiet.
+    fn key(&self) -> &str {
+        let key = self.text.split('=').next().unwrap_or_default();
+        match self.text.match_indices('=').nth(1) {
+            Some((index, _)) if PER_UUID_KEYS.contains(&key) => &self.text[..index],
+            _ => key,
+        }
+    }
+}
+
+// Every kernel parameter, with the module that asked for it, so conflicts can name both sides.
+#[derive(Default)]
+pub struct KernelCmdline {
+    params: Vec<Param>,
+}
+
+impl KernelCmdline {
+    // Identical parameters are only added once; the same key with a different value is a conflict.
+    pub fn add(&mut self, source: &'static str, text: &str) -> anyhow::Result<()> {
+        let param = Param {
+            text: text.to_string(),
+            source,
+        };
+
+        match self.params.iter().find(|existing| existing.key() == param.key()) {
+            Some(existing) if existing.text == param.text => Ok(()),
+            Some(existing) => bail!(
+                "Conflicting kernel parameters: {} wants '{}', but {} wants '{}'",
+                existing.source,
+                existing.text,
+                param.source,
+                param.text
+            ),
+            None => {
+                self.params.push(param);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn extend(&mut self, source: &'static str, texts: Vec<String>) -> anyhow::Result<()> {
+        for text in texts {
+            self.add(source, &text)?;
+        }
+        Ok(())
+    }
+
+    pub fn params(&self) -> Vec<String> {
+        self.params.iter().map(|param| param.text.clone()).collect()
+    }
+}
+
+// The TPM2 keyslot is also in /etc/crypttab.initramfs, but options given here take precedence over it.
+fn luks_params(luks_uuid: &str, tpm2: bool) -> Vec<String> {
+    let mut params = vec![format!("rd.luks.name={}=arch", luks_uuid)];
+
+    let options = root_luks_options(
+        &fetch_disk_type(),
+        &find_disk_option("integrity").unwrap_or("none".to_string()),
+        tpm2,
+    );
+    if !options.is_empty() {
+        params.push(format!("rd.luks.options={}={}", luks_uuid, options.join(",")));
+    }
+
+    params
+}
+
+// Naming the subvolume keeps booting independent of the default subvolume, which rollback may change.
+fn filesystem_params(filesystem: Filesystem, storage: Storage, layout: &[Subvolume]) -> Vec<String> {
+    let mut params = vec![format!("root={}", storage.root_device()), "rw".to_string()];
+    if filesystem == Filesystem::Btrfs {
+        params.push(format!("rootflags=subvol={}", root_subvolume(layout).name));
+    }
+    params
+}
+
+// The swap partition gets a new random key every boot, so only LVM's swap volume can be resumed from.
+fn swap_params(storage: Storage) -> Vec<String> {
+    if find_option("hibernation").unwrap_or_default() != "true" {
+        return Vec::new();
+    }
+
+    match storage.swap_device() {
+        Some(swap) => vec![format!("resume={}", swap)],
+        None => {
+            eprintln!("Hibernation needs the LVM storage layout, skipping resume=");
+            Vec::new()
+        }
+    }
+}
+
+// gpu_selected: 0 is NVIDIA, 1 is Intel, 2 is AMD.
+fn gpu_params() -> Vec<String> {
+    match find_option("gpu_selected").unwrap_or_default().as_str() {
+        "0" => vec!["nvidia-drm.modeset=1".to_string()],
+        _ => Vec::new(),
+    }
+}
+
+// AppArmor is installed and enabled by default, but only loads if it's in the LSM list.
+fn security_params() -> Vec<String> {
+    let mut params = vec!["lsm=landlock,lockdown,yama,integrity,apparmor,bpf".to_string()];
+    if find_option("no_mitigations").unwrap_or_default() == "true" {
+        params.push("mitigations=off".to_string());
+    }
+    params
+}
+
+// Plymouth is in the initramfs, but only shows its splash if asked to.
+fn ux_params() -> Vec<String> {
+    if find_option("boot_splash").unwrap_or_default() != "true" {
+        return Vec::new();
+    }
+    vec!["quiet".to_string(), "splash".to_string()]
+}
+
+// The one command line written to GRUB, systemd-boot's entries and the UKIs alike.
+// tpm2 is whether a TPM2 keyslot was actually enrolled, not just selected.
+pub fn build_cmdline(
+    disk: &str,
+    filesystem: Filesystem,
+    storage: Storage,
+    layout: &[Subvolume],
+    tpm2: bool,
+) -> anyhow::Result<Vec<String>> {
+    let luks_uuid = blkid_value(&partition_path(disk, 3), "UUID")?;
+
+    let mut cmdline = KernelCmdline::default();
+    cmdline.extend("LUKS", luks_params(&luks_uuid, tpm2))?;
+    cmdline.extend("filesystem", filesystem_params(filesystem, storage, layout))?;
+    cmdline.extend("swap", swap_params(storage))?;
+    cmdline.extend("GPU", gpu_params())?;
+    cmdline.extend("security", security_params())?;
+    cmdline.extend("boot splash", ux_params())?;
+
+    Ok(cmdline.params())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UUID: &str = "3c9a7e15-8b2d-4f60-a1c4-9d8e7f6a5b43";
+    const OTHER_UUID: &str = "0b6c1a8e-3f5d-4c2a-9e7b-5d8f1c2a3b4c";
+
+    #[test]
+    fn identical_parameters_are_added_once() {
+        let mut cmdline = KernelCmdline::default();
+        cmdline.add("boot splash", "quiet").unwrap();
+        cmdline.add("filesystem", "rw").unwrap();
+        cmdline.add("security", "quiet").unwrap();
+        assert_eq!(cmdline.params(), ["quiet", "rw"]);
+    }
+
+    #[test]
+    fn conflicting_values_name_both_sources() {
+        let mut cmdline = KernelCmdline::default();
+        cmdline.add("filesystem", "root=/dev/mapper/arch").unwrap();
+        let err = cmdline.add("LVM", "root=/dev/system/root").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Conflicting kernel parameters: filesystem wants 'root=/dev/mapper/arch', \
+            but LVM wants 'root=/dev/system/root'"
+        );
+        assert_eq!(cmdline.params(), ["root=/dev/mapper/arch"]);
+    }
+
+    #[test]
+    fn luks_keys_are_per_uuid() {
+        let mut cmdline = KernelCmdline::default();
+        cmdline.add("LUKS", &format!("rd.luks.name={}=arch", UUID)).unwrap();
+        cmdline
+            .add("LUKS", &format!("rd.luks.name={}=home", OTHER_UUID))
+            .unwrap();
+        cmdline
+            .add("LUKS", &format!("rd.luks.options={}=discard", UUID))
+            .unwrap();
+        cmdline
+            .add("LUKS", &format!("rd.luks.options={}=tpm2-device=auto", OTHER_UUID))
+            .unwrap();
+        assert_eq!(cmdline.params().len(), 4);
+    }
+
+    #[test]
+    fn luks_keys_conflict_for_the_same_uuid() {
+        let mut cmdline = KernelCmdline::default();
+        cmdline.add("LUKS", &format!("rd.luks.name={}=arch", UUID)).unwrap();
+        assert!(cmdline.add("LUKS", &format!("rd.luks.name={}=root", UUID)).is_err());
+
+        cmdline
+            .add("LUKS", &format!("rd.luks.options={}=discard", UUID))
+            .unwrap();
+        assert!(cmdline
+            .add("TPM2", &format!("rd.luks.options={}=tpm2-device=auto", UUID))
+            .is_err());
+    }
+
+    #[test]
+    fn extend_stops_at_the_first_conflict() {
+        let mut cmdline = KernelCmdline::default();
+        cmdline.add("security", "mitigations=auto").unwrap();
+        let texts = vec!["quiet".to_string(), "mitigations=off".to_string(), "splash".to_string()];
+        assert!(cmdline.extend("test", texts).is_err());
+        assert_eq!(cmdline.params(), ["mitigations=auto", "quiet"]);
+    }
+}
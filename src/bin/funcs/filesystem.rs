@@ -0,0 +1,218 @@
+use super::fstab::blkid_value;
+use super::layout::Subvolume;
+use super::mkfs::BtrfsFormat;
+use super::{find_disk_option, find_option, run_command};
+use nix::libc;
+use std::io;
+
+// The volume group created inside the LUKS2 container when disk_format selected LVM.
+pub const VOLUME_GROUP: &str = "system";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Filesystem {
+    Btrfs,
+    Ext4,
+    Xfs,
+    Bcachefs,
+    F2fs,
+}
+
+impl Filesystem {
+    pub const ALL: [Filesystem; 5] = [
+        Filesystem::Btrfs,
+        Filesystem::Ext4,
+        Filesystem::Xfs,
+        Filesystem::Bcachefs,
+        Filesystem::F2fs,
+    ];
+
+    pub fn from_options() -> Self {
+        let name = find_option("filesystem").unwrap_or("btrfs".to_string());
+        Filesystem::ALL
+            .into_iter()
+            .find(|filesystem| filesystem.name() == name)
+            .unwrap_or(Filesystem::Btrfs)
+    }
+
+    // As reported by lsblk's FSTYPE and used in fstab.
+    pub fn name(self) -> &'static str {
+        match self {
+            Filesystem::Btrfs => "btrfs",
+            Filesystem::Ext4 => "ext4",
+            Filesystem::Xfs => "xfs",
+            Filesystem::Bcachefs => "bcachefs",
+            Filesystem::F2fs => "f2fs",
+        }
+    }
+
+    // Userspace tools, for pacstrap.
+    pub fn package(self) -> &'static str {
+        match self {
+            Filesystem::Btrfs => "btrfs-progs",
+            Filesystem::Ext4 => "e2fsprogs",
+            Filesystem::Xfs => "xfsprogs",
+            Filesystem::Bcachefs => "bcachefs-tools",
+            Filesystem::F2fs => "f2fs-tools",
+        }
+    }
+
+    pub fn mkfs(self, device: &str, label: &str, btrfs_format: &BtrfsFormat) -> io::Result<()> {
+        let mut args: Vec<String> = match self {
+            Filesystem::Btrfs => BtrfsFormat {
+                label: label.to_string(),
+                ..btrfs_format.clone()
+            }
+            .mkfs_args(),
+            Filesystem::Ext4 => vec!["-F".to_string(), "-L".to_string(), label.to_string()],
+            Filesystem::Xfs => vec!["-f".to_string(), "-L".to_string(), label.to_string()],
+            Filesystem::Bcachefs => vec![
+                "format".to_string(),
+                "-f".to_string(),
+                "-L".to_string(),
+                label.to_string(),
+            ],
+            // The checksums f2fs leaves out by default.
+            Filesystem::F2fs => vec![
+                "-f".to_string(),
+                "-l".to_string(),
+                label.to_string(),
+                "-O".to_string(),
+                "extra_attr,inode_checksum,sb_checksum".to_string(),
+            ],
+        };
+        args.push(device.to_string());
+
+        let program = match self {
+            Filesystem::Bcachefs => "bcachefs".to_string(),
+            _ => format!("mkfs.{}", self.name()),
+        };
+        run_command(&program, &args.iter().map(String::as_str).collect::<Vec<&str>>())?;
+        Ok(())
+    }
+
+    // Only Btrfs gets subvolumes; fs_compression is its preset's compress option.
+    pub fn mount_options(self, fs_compression: &str) -> String {
+        match self {
+            Filesystem::Btrfs if !fs_compression.is_empty() => format!("noatime,{}", fs_compression),
+            _ => "noatime".to_string(),
+        }
+    }
+
+    // Btrfs and bcachefs check themselves on mount, and fsck.xfs does nothing.
+    pub fn fsck_pass(self, mountpoint: &str) -> u8 {
+        match self {
+            Filesystem::Ext4 | Filesystem::F2fs if mountpoint == "/" => 1,
+            Filesystem::Ext4 | Filesystem::F2fs => 2,
+            _ => 0,
+        }
+    }
+
+    // Added to HOOKS after filesystems; both come with the filesystem's userspace tools.
+    pub fn initramfs_hook(self) -> Option<&'static str> {
+        match self {
+            Filesystem::Btrfs => Some("btrfs"),
+            Filesystem::Bcachefs => Some("bcachefs"),
+            _ => None,
+        }
+    }
+
+    pub fn initramfs_binaries(self) -> Vec<&'static str> {
+        match self {
+            Filesystem::Btrfs => vec!["/usr/bin/btrfs"],
+            Filesystem::Bcachefs => vec!["/usr/bin/bcachefs"],
+            _ => vec![],
+        }
+    }
+
+    pub fn initramfs_modules(self) -> Vec<&'static str> {
+        match self {
+            Filesystem::Btrfs => BtrfsFormat::from_options().initramfs_modules(),
+            // f2fs asks the crypto API for crc32, which autodetect can't see.
+            Filesystem::F2fs => vec!["crc32_generic"],
+            _ => vec![],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Storage {
+    Partitions,
+    Lvm,
+}
+
+impl Storage {
+    pub fn from_disk_options() -> Self {
+        match find_disk_option("storage").unwrap_or_default().as_str() {
+            "lvm" => Storage::Lvm,
+            _ => Storage::Partitions,
+        }
+    }
+
+    // Read from the opened container instead, for when selected_disk.cfg is missing, e.g. when booted from the ISO.
+    // The volume group is activated too, as it isn't always activated when the container is opened by hand.
+    pub fn detect() -> io::Result<Self> {
+        if blkid_value("/dev/mapper/arch", "TYPE")? != "LVM2_member" {
+            return Ok(Storage::Partitions);
+        }
+        run_command("vgchange", &["-ay", VOLUME_GROUP])?;
+        Ok(Storage::Lvm)
+    }
+
+    pub fn root_device(self) -> String {
+        match self {
+            Storage::Partitions => "/dev/mapper/arch".to_string(),
+            Storage::Lvm => format!("/dev/{}/root", VOLUME_GROUP),
+        }
+    }
+
+    // LVM has its own home volume; with partitions /home is on the root filesystem.
+    pub fn home_device(self) -> Option<String> {
+        match self {
+            Storage::Partitions => None,
+            Storage::Lvm => Some(format!("/dev/{}/home", VOLUME_GROUP)),
+        }
+    }
+
+    // The swap partition is set up by crypttab instead, see fstab::build_crypttab().
+    pub fn swap_device(self) -> Option<String> {
+        match self {
+            Storage::Partitions => None,
+            Storage::Lvm => Some(format!("/dev/{}/swap", VOLUME_GROUP)),
+        }
+    }
+
+    // Subvolumes that would be hidden by the home volume are left out.
+    pub fn subvolumes(self, layout: &[Subvolume]) -> Vec<Subvolume> {
+        layout
+            .iter()
+            .filter(|subvol| {
+                self.home_device().is_none()
+                    || (subvol.mountpoint != "/home" && !subvol.mountpoint.starts_with("/home/"))
+            })
+            .cloned()
+            .collect()
+    }
+
+    // Creates the volumes inside /dev/mapper/arch, unless a previous run already did.
+    // Swap is as large as the RAM, so hibernation always fits.
+    pub fn create_volumes(self, root_size: &str) -> io::Result<()> {
+        if self == Storage::Partitions || run_command("vgs", &[VOLUME_GROUP]).is_ok() {
+            return Ok(());
+        }
+
+        let total_ram = unsafe { libc::sysconf(libc::_SC_PHYS_PAGES) * libc::sysconf(libc::_SC_PAGE_SIZE) };
+        let swap_size = format!("{}M", total_ram / (1024 * 1024));
+
+        run_command("pvcreate", &["/dev/mapper/arch"])?;
+        run_command("vgcreate", &[VOLUME_GROUP, "/dev/mapper/arch"])?;
+        run_command("lvcreate", &["-L", &swap_size, "-n", "swap", VOLUME_GROUP])?;
+        run_command("lvcreate", &["-L", root_size, "-n", "root", VOLUME_GROUP])?;
+        run_command("lvcreate", &["-l", "100%FREE", "-n", "home", VOLUME_GROUP])?;
+        println!(
+            "Created the root, home and swap volumes in volume group {}",
+            VOLUME_GROUP
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,281 @@
+use super::filesystem::Filesystem;
+use super::layout::Subvolume;
+use super::run_command;
+use std::io;
+
+const FSTAB_HEADER: &str = "# Static information about the filesystems.
+# See fstab(5) for details.
+
+# <file system> <dir> <type> <options> <dump> <pass>
+";
+
+const CRYPTTAB_HEADER: &str = "# Configuration for encrypted block devices.
+# See crypttab(5) for details.
+
+# <name> <device> <password> <options>
+";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FstabEntry {
+    pub source: String,
+    pub target: String,
+    pub fstype: String,
+    pub options: String,
+    pub dump: u8,
+    pub pass: u8,
+}
+
+impl FstabEntry {
+    fn new(source: String, target: &str, fstype: &str, options: &str, pass: u8) -> Self {
+        FstabEntry {
+            source,
+            target: target.to_string(),
+            fstype: fstype.to_string(),
+            options: options.to_string(),
+            dump: 0,
+            pass,
+        }
+    }
+}
+
+// Identifiers of the installed system's filesystems and partitions, as reported by blkid.
+pub struct FstabSources {
+    pub root_uuid: String,
+    pub home_uuid: Option<String>,
+    pub esp_uuid: String,
+    pub swap_partuuid: Option<String>,
+    pub swap_device: Option<String>,
+}
+
+// Btrfs checks itself on mount, so its subvolumes don't get an fsck pass.
+pub fn build_fstab(
+    sources: &FstabSources,
+    filesystem: Filesystem,
+    layout: &[Subvolume],
+    fs_compression: &str,
+) -> Vec<FstabEntry> {
+    let mut entries: Vec<FstabEntry> = if filesystem == Filesystem::Btrfs {
+        layout
+            .iter()
+            .map(|subvol| {
+                FstabEntry::new(
+                    format!("UUID={}", sources.root_uuid),
+                    &subvol.mountpoint,
+                    "btrfs",
+                    &subvol.mount_options(fs_compression),
+                    0,
+                )
+            })
+            .collect()
+    } else {
+        vec![FstabEntry::new(
+            format!("UUID={}", sources.root_uuid),
+            "/",
+            filesystem.name(),
+            &filesystem.mount_options(fs_compression),
+            filesystem.fsck_pass("/"),
+        )]
+    };
+
+    if let Some(ref home_uuid) = sources.home_uuid {
+        entries.push(FstabEntry::new(
+            format!("UUID={}", home_uuid),
+            "/home",
+            filesystem.name(),
+            &filesystem.mount_options(fs_compression),
+            filesystem.fsck_pass("/home"),
+        ));
+    }
+
+    entries.push(FstabEntry::new(
+        format!("UUID={}", sources.esp_uuid),
+        "/boot",
+        "vfat",
+        "rw,noatime,nodev,nosuid,noexec,fmask=0077,dmask=0077,utf8,errors=remount-ro",
+        2,
+    ));
+
+    // Opened by crypttab with a random key on every boot; see build_crypttab().
+    if sources.swap_partuuid.is_some() {
+        entries.push(FstabEntry::new(
+            "/dev/mapper/swap".to_string(),
+            "none",
+            "swap",
+            "defaults",
+            0,
+        ));
+    } else if let Some(ref swap_device) = sources.swap_device {
+        entries.push(FstabEntry::new(swap_device.clone(), "none", "swap", "defaults", 0));
+    }
+
+    entries.push(FstabEntry::new(
+        "tmpfs".to_string(),
+        "/tmp",
+        "tmpfs",
+        "rw,nosuid,nodev,noatime,mode=1777",
+        0,
+    ));
+
+    entries
+}
+
+pub fn render_fstab(entries: &[FstabEntry]) -> String {
+    let mut contents = FSTAB_HEADER.to_string();
+
+    for entry in entries {
+        contents.push_str(&format!(
+            "{:<47} {:<23} {:<7} {} {} {}\n",
+            entry.source, entry.target, entry.fstype, entry.options, entry.dump, entry.pass
+        ));
+    }
+
+    contents
+}
+
+// The swap partition has no filesystem UUID, since it's re-encrypted with a new random key every boot.
+pub fn build_crypttab(sources: &FstabSources) -> String {
+    let mut contents = CRYPTTAB_HEADER.to_string();
+
+    if let Some(ref partuuid) = sources.swap_partuuid {
+        contents.push_str(&format!(
+            "swap PARTUUID={} /dev/urandom swap,cipher=aes-xts-plain64,size=512,sector-size=4096\n",
+            partuuid
+        ));
+    }
+
+    contents
+}
+
+// Options for the root container, shared by crypttab.initramfs and rd.luks.options.
+// dm-integrity only passes discards through when it computes the tags itself, not when dm-crypt provides them
+// as it does for LUKS2, so containers with integrity aren't TRIMmed.
+pub fn root_luks_options(disk_type: &str, integrity: &str, tpm2: bool) -> Vec<&'static str> {
+    let mut options = Vec::new();
+    if disk_type != "HDD" && integrity == "none" {
+        options.push("discard");
+    }
+    if tpm2 {
+        options.push("tpm2-device=auto");
+    }
+    options
+}
+
+// sd-encrypt copies /etc/crypttab.initramfs into the initramfs as /etc/crypttab.
+pub fn build_root_crypttab(luks_uuid: &str, options: &[&str]) -> String {
+    let mut contents = CRYPTTAB_HEADER.to_string();
+
+    if options.is_empty() {
+        contents.push_str(&format!("arch UUID={} none\n", luks_uuid));
+    } else {
+        contents.push_str(&format!("arch UUID={} none {}\n", luks_uuid, options.join(",")));
+    }
+
+    contents
+}
+
+// Reads a tag such as UUID or PARTUUID from a block device.
+pub fn blkid_value(device: &str, tag: &str) -> io::Result<String> {
+    let output = run_command("blkid", &["-s", tag, "-o", "value", device])?;
+    let value = String::from_utf8_lossy(&output.stdout).trim().to_string();
+
+    if value.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} has no {}", device, tag),
+        ));
+    }
+    Ok(value)
+}
+
+// Every UUID= and PARTUUID= source must resolve to a block device, otherwise the boot stops at an emergency shell.
+pub fn verify_fstab(entries: &[FstabEntry]) -> io::Result<()> {
+    for entry in entries {
+        if !entry.source.starts_with("UUID=") && !entry.source.starts_with("PARTUUID=") {
+            continue;
+        }
+
+        if run_command("blkid", &["-o", "device", "-t", &entry.source]).is_err() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "fstab entry for {} uses {}, which blkid can't find",
+                    entry.target, entry.source
+                ),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::funcs::layout::parse_layout;
+
+    const LAYOUT: &str = "@root / noatime default no yes
+@home /home noatime default no yes
+@log /var/log noatime zstd no no
+@libvirt /var/lib/libvirt/images noatime none yes no
+";
+
+    fn sources() -> FstabSources {
+        FstabSources {
+            root_uuid: "0b6c1a8e-3f5d-4c2a-9e7b-5d8f1c2a3b4c".to_string(),
+            home_uuid: None,
+            esp_uuid: "A1B2-C3D4".to_string(),
+            swap_partuuid: None,
+            swap_device: None,
+        }
+    }
+
+    fn golden(name: &str) -> String {
+        let path = format!("{}/tests/golden/{}", env!("CARGO_MANIFEST_DIR"), name);
+        std::fs::read_to_string(&path).unwrap_or_else(|e| panic!("Failed to read {}: {}", path, e))
+    }
+
+    #[test]
+    fn btrfs_subvolumes_with_swap() {
+        let sources = FstabSources {
+            swap_partuuid: Some("7d2f9c4e-1a3b-4e5f-8a9b-0c1d2e3f4a5b".to_string()),
+            ..sources()
+        };
+        let layout = parse_layout(LAYOUT).unwrap();
+
+        let entries = build_fstab(&sources, Filesystem::Btrfs, &layout, "compress=zstd:1");
+        assert_eq!(render_fstab(&entries), golden("btrfs_swap.fstab"));
+        assert_eq!(build_crypttab(&sources), golden("btrfs_swap.crypttab"));
+    }
+
+    #[test]
+    fn lvm_with_ext4() {
+        let sources = FstabSources {
+            home_uuid: Some("5e4d3c2b-1a09-4f8e-b7d6-c5b4a3928170".to_string()),
+            swap_device: Some("/dev/system/swap".to_string()),
+            ..sources()
+        };
+        let layout = parse_layout(LAYOUT).unwrap();
+
+        let entries = build_fstab(&sources, Filesystem::Ext4, &layout, "");
+        assert_eq!(render_fstab(&entries), golden("lvm_ext4.fstab"));
+        assert_eq!(build_crypttab(&sources), golden("lvm_ext4.crypttab"));
+    }
+
+    #[test]
+    fn integrity_root_crypttab() {
+        let options = root_luks_options("NVME", "hmac-sha256", true);
+        assert_eq!(options, ["tpm2-device=auto"]);
+        assert_eq!(
+            build_root_crypttab("3c9a7e15-8b2d-4f60-a1c4-9d8e7f6a5b43", &options),
+            golden("integrity_root.crypttab")
+        );
+    }
+
+    #[test]
+    fn root_crypttab_without_options() {
+        let options = root_luks_options("HDD", "none", false);
+        assert!(options.is_empty());
+        assert!(build_root_crypttab("3c9a7e15-8b2d-4f60-a1c4-9d8e7f6a5b43", &options)
+            .ends_with("arch UUID=3c9a7e15-8b2d-4f60-a1c4-9d8e7f6a5b43 none\n"));
+    }
+}
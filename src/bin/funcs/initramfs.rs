@@ -0,0 +1,170 @@
+use super::filesystem::{Filesystem, Storage};
+use super::{find_disk_option, find_option, run_command};
+use anyhow::{bail, Context};
+use std::fs;
+use std::path::Path;
+
+pub const MKINITCPIO_CONF: &str = "/etc/mkinitcpio.conf";
+
+// A file each hook puts into the initramfs, to check that the hook actually ran.
+const HOOK_FILES: [(&str, &str); 4] = [
+    ("sd-encrypt", "usr/lib/systemd/systemd-cryptsetup"),
+    ("lvm2", "usr/bin/lvm"),
+    ("plymouth", "usr/bin/plymouth"),
+    ("sd-vconsole", "etc/vconsole.conf"),
+];
+
+pub struct InitramfsConfig {
+    pub modules: Vec<&'static str>,
+    pub binaries: Vec<&'static str>,
+    pub hooks: Vec<&'static str>,
+}
+
+impl InitramfsConfig {
+    pub fn from_options(filesystem: Filesystem, storage: Storage) -> Self {
+        let gpu = find_option("gpu_selected").unwrap_or_default();
+        // NVIDIA's driver isn't installed by the installer itself; without it, nouveau is used.
+        let nvidia = gpu == "0" && Path::new("/usr/bin/nvidia-smi").exists();
+        let mut modules = Vec::new();
+
+        // Early KMS, so the splash and the LUKS2 prompt use the native resolution.
+        // gpu_selected: 0 is NVIDIA, 1 is Intel, 2 is AMD.
+        match gpu.as_str() {
+            "0" if nvidia => modules.extend(["nvidia", "nvidia_modeset", "nvidia_uvm", "nvidia_drm"]),
+            "1" => modules.push("i915"),
+            "2" => modules.push("amdgpu"),
+            _ => {}
+        }
+
+        if find_option("tpm2_unlock").unwrap_or_default() == "true" {
+            modules.extend(["tpm_tis", "tpm_crb"]);
+        }
+
+        // sd-encrypt only opens the root container if the initramfs can also set up its dm-integrity layer.
+        match find_disk_option("integrity").unwrap_or("none".to_string()).as_str() {
+            "hmac-sha256" => modules.extend(["dm_integrity", "hmac", "sha256"]),
+            "aead" => modules.extend(["dm_integrity", "gcm"]),
+            _ => {}
+        };
+
+        modules.extend(filesystem.initramfs_modules());
+
+        let mut hooks = vec!["base", "systemd", "autodetect", "microcode", "modconf"];
+        // The kms hook would also add nouveau, which conflicts with the NVIDIA modules above.
+        if !nvidia {
+            hooks.push("kms");
+        }
+        hooks.extend(["keyboard", "sd-vconsole", "block"]);
+        // Before sd-encrypt, so the LUKS2 passphrase is asked for on the splash instead of the console.
+        if find_option("boot_splash").unwrap_or_default() == "true" {
+            hooks.push("plymouth");
+        }
+        hooks.push("sd-encrypt");
+        // lvm2 has to activate the volume group between unlocking the container and mounting root.
+        if storage == Storage::Lvm {
+            hooks.push("lvm2");
+        }
+        hooks.push("filesystems");
+        hooks.extend(filesystem.initramfs_hook());
+        // Like resume= on the command line, only the LVM swap volume can be resumed from; see cmdline.rs.
+        if find_option("hibernation").unwrap_or_default() == "true" && storage.swap_device().is_some() {
+            hooks.push("resume");
+        }
+        hooks.push("fsck");
+
+        InitramfsConfig {
+            modules,
+            binaries: filesystem.initramfs_binaries(),
+            hooks,
+        }
+    }
+
+    pub fn render(&self) -> String {
+        format!(
+            "# Generated by the Arch Flux installer from user_selections.cfg.\n\
+            # See mkinitcpio.conf(5) for details.\n\n\
+            MODULES=({})\n\
+            BINARIES=({})\n\
+            FILES=()\n\
+            HOOKS=({})\n\
+            COMPRESSION=\"zstd\"\n\
+            COMPRESSION_OPTIONS=(-1)\n",
+            self.modules.join(" "),
+            self.binaries.join(" "),
+            self.hooks.join(" ")
+        )
+    }
+
+    pub fn write(&self) -> anyhow::Result<()> {
+        fs::write(MKINITCPIO_CONF, self.render()).with_context(|| format!("Failed to write {}", MKINITCPIO_CONF))
+    }
+
+    // Checks an initramfs or UKI built by mkinitcpio against this config, using lsinitcpio.
+    pub fn verify(&self, image: &str, kernel: &str) -> anyhow::Result<()> {
+        let kernel_release = kernel_release(kernel)?;
+        let output = run_command("lsinitcpio", &[image]).with_context(|| format!("Failed to list {}", image))?;
+        let listing = String::from_utf8_lossy(&output.stdout);
+        let contains = |file: &str| {
+            listing
+                .lines()
+                .any(|line| line.trim_start_matches("./").ends_with(file))
+        };
+
+        for module in &self.modules {
+            // Resolves aliases such as sha256; built-in modules aren't in the initramfs.
+            let output = run_command("modinfo", &["-k", &kernel_release, "-F", "filename", module])?;
+            let filename = String::from_utf8_lossy(&output.stdout).trim().to_string();
+            if filename.starts_with("(builtin)") {
+                continue;
+            }
+
+            let file_name = Path::new(&filename).file_name().unwrap_or_default().to_string_lossy();
+            if !contains(&file_name) {
+                bail!("{} is missing the {} module", image, module);
+            }
+        }
+
+        for binary in &self.binaries {
+            if !contains(binary.trim_start_matches('/')) {
+                bail!("{} is missing {}", image, binary);
+            }
+        }
+
+        for (hook, file) in HOOK_FILES {
+            if self.hooks.contains(&hook) && !contains(file) {
+                bail!("{} is missing {}, added by the {} hook", image, file, hook);
+            }
+        }
+
+        if Path::new("/etc/crypttab.initramfs").exists() && !contains("etc/crypttab") {
+            bail!("{} is missing /etc/crypttab.initramfs", image);
+        }
+
+        // amd-ucode and intel-ucode aren't installed in VMs, there the microcode hook adds nothing.
+        let ucode = ["/boot/amd-ucode.img", "/boot/intel-ucode.img"]
+            .iter()
+            .any(|ucode| Path::new(ucode).exists());
+        let has_microcode = listing.lines().any(|line| {
+            let line = line.trim_start_matches("./");
+            line.starts_with("kernel/x86/microcode/") && line.ends_with(".bin")
+        });
+        if self.hooks.contains(&"microcode") && ucode && !has_microcode {
+            bail!("{} is missing the CPU microcode, added by the microcode hook", image);
+        }
+
+        println!("Verified {}", image);
+        Ok(())
+    }
+}
+
+// Kernel packages record their name in /usr/lib/modules/<release>/pkgbase.
+fn kernel_release(kernel: &str) -> anyhow::Result<String> {
+    for entry in fs::read_dir("/usr/lib/modules")? {
+        let path = entry?.path();
+        let pkgbase = fs::read_to_string(path.join("pkgbase")).unwrap_or_default();
+        if pkgbase.trim() == kernel {
+            return Ok(path.file_name().unwrap_or_default().to_string_lossy().to_string());
+        }
+    }
+    bail!("No modules directory found for {}", kernel)
+}
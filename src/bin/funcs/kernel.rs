@@ -0,0 +1,107 @@
+use super::find_option;
+
+pub const KERNELS: [&str; 4] = ["linux", "linux-lts", "linux-zen", "linux-hardened"];
+
+// dkms_modules= names, with the package that builds each out-of-tree module against every kernel's headers.
+pub const DKMS_MODULES: [(&str, &str); 2] = [("nvidia", "nvidia-open-dkms"), ("virtualbox", "virtualbox-host-dkms")];
+
+// Unknown modules are dropped.
+pub fn dkms_packages(modules: &str) -> Vec<&'static str> {
+    DKMS_MODULES
+        .iter()
+        .filter(|(name, _)| modules.split(',').any(|module| module == *name))
+        .map(|(_, package)| *package)
+        .collect()
+}
+
+pub struct Kernels {
+    pub selected: Vec<String>,
+    pub default: String,
+}
+
+impl Kernels {
+    // Unknown kernels are dropped, and the default falls back to the first selected kernel.
+    pub fn from_options() -> Self {
+        let mut selected: Vec<String> = find_option("kernels")
+            .unwrap_or("linux,linux-lts".to_string())
+            .split(',')
+            .filter(|kernel| KERNELS.contains(kernel))
+            .map(String::from)
+            .collect();
+        if selected.is_empty() {
+            selected.push("linux".to_string());
+        }
+
+        let default = find_option("default_kernel")
+            .ok()
+            .filter(|kernel| selected.contains(kernel))
+            .unwrap_or(selected[0].clone());
+
+        Kernels { selected, default }
+    }
+
+    // The default kernel first, then the LTS kernel to fall back to if a newer kernel breaks booting.
+    pub fn boot_order(&self) -> Vec<&str> {
+        let mut order = vec![self.default.as_str()];
+        if self.selected.iter().any(|kernel| kernel == "linux-lts") && self.default != "linux-lts" {
+            order.push("linux-lts");
+        }
+        let others: Vec<&str> = self
+            .selected
+            .iter()
+            .map(String::as_str)
+            .filter(|kernel| !order.contains(kernel))
+            .collect();
+        order.extend(others);
+        order
+    }
+
+    // Headers are only needed to build DKMS modules, so they're only added if one is being installed.
+    pub fn packages(&self, dkms_packages: &[&str]) -> Vec<String> {
+        let mut packages = self.selected.clone();
+        if !dkms_packages.is_empty() {
+            packages.extend(self.selected.iter().map(|kernel| format!("{}-headers", kernel)));
+            packages.extend(dkms_packages.iter().map(|package| package.to_string()));
+        }
+        packages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kernels(selected: &[&str]) -> Kernels {
+        Kernels {
+            selected: selected.iter().map(|kernel| kernel.to_string()).collect(),
+            default: selected[0].to_string(),
+        }
+    }
+
+    #[test]
+    fn headers_are_added_for_every_kernel_with_dkms() {
+        let packages = kernels(&["linux", "linux-lts"]).packages(&dkms_packages("nvidia,virtualbox"));
+        assert_eq!(
+            packages,
+            [
+                "linux",
+                "linux-lts",
+                "linux-headers",
+                "linux-lts-headers",
+                "nvidia-open-dkms",
+                "virtualbox-host-dkms"
+            ]
+        );
+    }
+
+    #[test]
+    fn no_headers_without_dkms() {
+        let packages = kernels(&["linux-zen"]).packages(&dkms_packages(""));
+        assert_eq!(packages, ["linux-zen"]);
+    }
+
+    #[test]
+    fn unknown_dkms_modules_are_dropped() {
+        assert_eq!(dkms_packages("zfs,virtualbox"), ["virtualbox-host-dkms"]);
+    }
+}
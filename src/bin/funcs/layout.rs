@@ -0,0 +1,243 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub const LAYOUT_PATH: &str = "/root/arch-flux/subvolumes.cfg";
+
+const DEFAULT_LAYOUT: &str = "# Btrfs subvolume layout, one subvolume per line.
+# Mount options must not contain 'subvol' or 'compress', they're added from the subvolume's name and
+# the compression preset. Btrfs applies compress mount options to the whole filesystem, so per subvolume
+# compression is instead set as a property: default (the preset's), zstd, lzo, zlib, or none.
+# NOCOW only applies to files created after the flag is set, so it's set before pacstrap runs.
+# Use NOCOW for VM images, databases and swapfiles; their files are neither compressed nor snapshotted,
+# as a separate subvolume isn't part of its parent's snapshots.
+#
+# <subvolume>  <mountpoint>               <mount options>  <compression>  <nocow>  <snapshot>
+@root          /                          noatime          default        no       yes
+@home          /home                      noatime          default        no       yes
+@srv           /srv                       noatime          default        no       no
+@snapshots     /.snapshots                noatime          default        no       no
+@pkg           /var/cache/pacman/pkg      noatime          none           no       no
+@log           /var/log                   noatime          zstd           no       no
+@libvirt       /var/lib/libvirt/images    noatime          none           yes      no
+#@swap         /swap                      noatime          none           yes      no
+";
+
+const COMPRESSION_VALUES: [&str; 5] = ["default", "zstd", "lzo", "zlib", "none"];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Subvolume {
+    pub name: String,
+    pub mountpoint: String,
+    pub options: String,
+    pub compression: String,
+    pub nocow: bool,
+    pub snapshot: bool,
+}
+
+impl Subvolume {
+    // Parents have to be mounted before their children, e.g. / before /var/log.
+    pub fn depth(&self) -> usize {
+        Path::new(&self.mountpoint).components().count()
+    }
+
+    // fs_compression is the preset's filesystem-wide option, see compression_mount_option().
+    pub fn mount_options(&self, fs_compression: &str) -> String {
+        let mut options = vec![self.options.as_str()];
+        if !fs_compression.is_empty() {
+            options.push(fs_compression);
+        }
+        format!("{},subvol=/{}", options.join(","), self.name)
+    }
+
+    // The value for "btrfs property set <mountpoint> compression", unless the preset's is used.
+    pub fn compression_property(&self) -> Option<&str> {
+        match self.compression.as_str() {
+            "default" => None,
+            compression => Some(compression),
+        }
+    }
+}
+
+// Presets for the filesystem-wide compress mount option.
+// Slower disks gain more from a higher ratio. compress-force isn't used, because it takes precedence over
+// the compression property, so subvolumes set to none in subvolumes.cfg would still be compressed.
+pub fn compression_mount_option(preset: &str, disk_type: &str) -> &'static str {
+    let preset = match (preset, disk_type) {
+        ("auto", "NVME") => "nvme",
+        ("auto", "HDD") => "hdd",
+        ("auto", _) => "sata",
+        (preset, _) => preset,
+    };
+
+    match preset {
+        "nvme" => "compress=zstd:1",
+        "hdd" => "compress=zstd:3",
+        "none" => "",
+        _ => "compress=zstd:3",
+    }
+}
+
+fn parse_flag(value: &str, line_number: usize) -> Result<bool, String> {
+    match value {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(format!("line {}: expected 'yes' or 'no', got '{}'", line_number, value)),
+    }
+}
+
+pub fn parse_layout(contents: &str) -> Result<Vec<Subvolume>, String> {
+    let mut layout = Vec::new();
+
+    for (index, line) in contents.lines().enumerate() {
+        let line_number = index + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut columns: Vec<&str> = line.split_whitespace().collect();
+        // Layouts from before the compression column was added use the preset's compression.
+        if columns.len() == 5 {
+            columns.insert(3, "default");
+        }
+        if columns.len() != 6 {
+            return Err(format!(
+                "line {}: expected 6 columns, got {}",
+                line_number,
+                columns.len()
+            ));
+        }
+
+        let subvolume = Subvolume {
+            name: columns[0].to_string(),
+            mountpoint: columns[1].to_string(),
+            options: columns[2].to_string(),
+            compression: columns[3].to_string(),
+            nocow: parse_flag(columns[4], line_number)?,
+            snapshot: parse_flag(columns[5], line_number)?,
+        };
+
+        if !subvolume.name.starts_with('@') || subvolume.name.contains('/') {
+            return Err(format!(
+                "line {}: subvolume names must start with '@' and not contain '/'",
+                line_number
+            ));
+        }
+        if !subvolume.mountpoint.starts_with('/') {
+            return Err(format!("line {}: mountpoint must be an absolute path", line_number));
+        }
+        if let Some(option) = subvolume
+            .options
+            .split(',')
+            .find(|option| option.starts_with("subvol") || option.starts_with("compress"))
+        {
+            return Err(format!("line {}: remove the '{}' mount option", line_number, option));
+        }
+        if !COMPRESSION_VALUES.contains(&subvolume.compression.as_str()) {
+            return Err(format!(
+                "line {}: compression must be one of {}",
+                line_number,
+                COMPRESSION_VALUES.join(", ")
+            ));
+        }
+        // Snapshots make the next write to every NOCOW file copy-on-write again, fragmenting it anyway.
+        if subvolume.nocow && subvolume.snapshot {
+            return Err(format!("line {}: NOCOW subvolumes can't be snapshotted", line_number));
+        }
+
+        layout.push(subvolume);
+    }
+
+    let mut names = HashSet::new();
+    let mut mountpoints = HashSet::new();
+    for subvolume in &layout {
+        if !names.insert(&subvolume.name) {
+            return Err(format!("subvolume {} is listed more than once", subvolume.name));
+        }
+        if !mountpoints.insert(&subvolume.mountpoint) {
+            return Err(format!("mountpoint {} is used more than once", subvolume.mountpoint));
+        }
+    }
+    if !mountpoints.contains(&"/".to_string()) {
+        return Err("no subvolume is mounted at /".to_string());
+    }
+
+    layout.sort_by_key(|subvolume| subvolume.depth());
+    Ok(layout)
+}
+
+// Writes the default layout on first use, so it can be edited before running the installer.
+pub fn load_layout() -> io::Result<Vec<Subvolume>> {
+    if !Path::new(LAYOUT_PATH).exists() {
+        fs::write(LAYOUT_PATH, DEFAULT_LAYOUT)?;
+    }
+
+    let contents = fs::read_to_string(LAYOUT_PATH)?;
+    parse_layout(&contents)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid {}: {}", LAYOUT_PATH, e)))
+}
+
+pub fn root_subvolume(layout: &[Subvolume]) -> &Subvolume {
+    layout
+        .iter()
+        .find(|subvolume| subvolume.mountpoint == "/")
+        .expect("parse_layout ensures a subvolume is mounted at /")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_layout_parses() {
+        let layout = parse_layout(DEFAULT_LAYOUT).unwrap();
+        assert_eq!(root_subvolume(&layout).name, "@root");
+        assert_eq!(layout.len(), 7);
+    }
+
+    #[test]
+    fn rejects_duplicate_names() {
+        let contents = "@root / noatime default no yes\n@root /home noatime default no yes\n";
+        assert_eq!(
+            parse_layout(contents).unwrap_err(),
+            "subvolume @root is listed more than once"
+        );
+    }
+
+    #[test]
+    fn rejects_duplicate_mountpoints() {
+        let contents = "@root / noatime default no yes\n@home / noatime default no yes\n";
+        assert_eq!(
+            parse_layout(contents).unwrap_err(),
+            "mountpoint / is used more than once"
+        );
+    }
+
+    #[test]
+    fn requires_a_root_subvolume() {
+        let contents = "@home /home noatime default no yes\n";
+        assert_eq!(parse_layout(contents).unwrap_err(), "no subvolume is mounted at /");
+    }
+
+    #[test]
+    fn sorts_by_mountpoint_depth() {
+        let contents = "@log /var/log noatime zstd no no\n\
+                        @home /home noatime default no yes\n\
+                        @root / noatime default no yes\n";
+        let layout = parse_layout(contents).unwrap();
+        let mountpoints: Vec<&str> = layout.iter().map(|subvolume| subvolume.mountpoint.as_str()).collect();
+        assert_eq!(mountpoints, ["/", "/home", "/var/log"]);
+    }
+
+    #[test]
+    fn accepts_lines_without_the_compression_column() {
+        let contents = "@root / noatime no yes\n@libvirt /var/lib/libvirt/images noatime yes no\n";
+        let layout = parse_layout(contents).unwrap();
+        assert_eq!(layout[0].compression, "default");
+        assert!(layout[0].snapshot);
+        assert_eq!(layout[1].compression, "default");
+        assert!(layout[1].nocow);
+    }
+}
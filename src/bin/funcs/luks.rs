@@ -0,0 +1,71 @@
+use super::{partition_path, prompt_u8};
+use libcryptsetup_rs::consts::flags::CryptActivate;
+use libcryptsetup_rs::consts::vals::{CryptStatusInfo, EncryptionFormat};
+use libcryptsetup_rs::{CryptInit, LibcryptErr};
+use std::fs;
+use std::path::Path;
+
+// Opens the root partition's LUKS2 container as "arch", or checks that an already opened "arch" is on it.
+pub fn open_luks_container(disk: &str) -> Result<(), LibcryptErr> {
+    let root_part = partition_path(disk, 3);
+
+    match libcryptsetup_rs::status(None, "arch")? {
+        CryptStatusInfo::Active | CryptStatusInfo::Busy => {
+            let mut device = CryptInit::init_by_name_and_header("arch", None)?;
+            let backing_device = device.status_handle().get_device_path()?.to_path_buf();
+
+            // Compare the resolved paths, as the backing device may be reported through a symlink.
+            let backing_device = fs::canonicalize(&backing_device).unwrap_or(backing_device);
+            let expected_device = fs::canonicalize(&root_part).unwrap_or(root_part.clone().into());
+
+            if backing_device != expected_device {
+                return Err(LibcryptErr::Other(format!(
+                    "/dev/mapper/arch is opened on {}, not on the selected disk's root partition {}",
+                    backing_device.display(),
+                    expected_device.display()
+                )));
+            }
+            println!(
+                "LUKS2 container on {} is already opened as /dev/mapper/arch",
+                &root_part
+            );
+        }
+        _ => {
+            let mut device = CryptInit::init(Path::new(&root_part))?;
+            if device
+                .context_handle()
+                .load::<()>(Some(EncryptionFormat::Luks2), None)
+                .is_err()
+            {
+                return Err(LibcryptErr::Other(format!(
+                    "No LUKS2 container found on {}, did you run the disk format utility?",
+                    &root_part
+                )));
+            }
+
+            for attempt in 1..=3 {
+                let password = prompt_u8(&format!(
+                    "\nEnter the password for the LUKS2 container on {}: ",
+                    &root_part
+                ));
+
+                // The persistent flags stored in the header by disk_format are applied automatically.
+                match device.activate_handle().activate_by_passphrase(
+                    Some("arch"),
+                    None,
+                    &password,
+                    CryptActivate::empty(),
+                ) {
+                    Ok(_) => {
+                        println!("Opened the LUKS2 container as /dev/mapper/arch");
+                        return Ok(());
+                    }
+                    Err(e) if attempt < 3 => eprintln!("Failed to open the LUKS2 container, try again: {}", e),
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
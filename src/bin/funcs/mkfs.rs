@@ -0,0 +1,182 @@
+use super::find_option;
+use regex::Regex;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+// (mkfs.btrfs name, sysfs name under /sys/fs/btrfs/features, first kernel that can mount it)
+const FEATURES: [(&str, &str, (u32, u32)); 6] = [
+    ("extref", "extended_iref", (3, 7)),
+    ("skinny-metadata", "skinny_metadata", (3, 10)),
+    ("no-holes", "no_holes", (3, 14)),
+    ("free-space-tree", "free_space_tree", (4, 5)),
+    ("block-group-tree", "block_group_tree", (6, 1)),
+    ("raid-stripe-tree", "raid_stripe_tree", (6, 7)),
+];
+
+// (checksum, first kernel and btrfs-progs version that support it)
+const CHECKSUMS: [(&str, (u32, u32)); 4] = [
+    ("crc32c", (3, 0)),
+    ("xxhash", (5, 5)),
+    ("sha256", (5, 5)),
+    ("blake2", (5, 5)),
+];
+
+#[derive(Clone)]
+pub struct BtrfsFormat {
+    pub checksum: String,
+    pub nodesize: String,
+    pub label: String,
+    pub metadata: String,
+    pub features: Vec<String>,
+}
+
+fn parse_version(text: &str) -> Option<(u32, u32)> {
+    let caps = Regex::new(r"(\d+)\.(\d+)").ok()?.captures(text)?;
+    Some((caps[1].parse().ok()?, caps[2].parse().ok()?))
+}
+
+// Version of a kernel package in the sync database, or of the running kernel if pacman doesn't know it.
+pub fn target_kernel_version(package: &str) -> (u32, u32) {
+    let pacman_version = Command::new("pacman")
+        .args(["-Si", package])
+        .output()
+        .ok()
+        .map(|output| String::from_utf8_lossy(&output.stdout).to_string())
+        .and_then(|info| {
+            info.lines()
+                .find(|line| line.starts_with("Version"))
+                .and_then(|line| line.split(':').nth(1).and_then(parse_version))
+        });
+
+    pacman_version.unwrap_or_else(|| {
+        let release = fs::read_to_string("/proc/sys/kernel/osrelease").unwrap_or_default();
+        parse_version(&release).unwrap_or((0, 0))
+    })
+}
+
+fn btrfs_progs_version() -> (u32, u32) {
+    Command::new("mkfs.btrfs")
+        .arg("--version")
+        .output()
+        .ok()
+        .and_then(|output| parse_version(&String::from_utf8_lossy(&output.stdout)))
+        .unwrap_or((0, 0))
+}
+
+// mkfs.btrfs prints its feature list to stderr.
+fn btrfs_progs_features() -> String {
+    Command::new("mkfs.btrfs")
+        .args(["-O", "list-all"])
+        .output()
+        .map(|output| String::from_utf8_lossy(&output.stderr).to_string())
+        .unwrap_or_default()
+}
+
+impl BtrfsFormat {
+    pub fn from_options() -> Self {
+        let option = |name: &str, default: &str| find_option(name).unwrap_or(default.to_string());
+
+        BtrfsFormat {
+            checksum: option("btrfs_checksum", "crc32c"),
+            nodesize: option("btrfs_nodesize", "16k"),
+            label: option("btrfs_label", "arch"),
+            metadata: option("btrfs_metadata", "dup"),
+            features: option("btrfs_features", "free-space-tree,block-group-tree")
+                .split(',')
+                .filter(|feature| !feature.is_empty() && *feature != "none")
+                .map(String::from)
+                .collect(),
+        }
+    }
+
+    // Checked before anything is formatted; the running kernel has to mount it now, the target kernel on boot.
+    pub fn validate(&self, target_kernel: (u32, u32)) -> Result<(), String> {
+        let progs = btrfs_progs_version();
+        let progs_features = btrfs_progs_features();
+
+        let (_, checksum_version) = CHECKSUMS
+            .iter()
+            .find(|(checksum, _)| *checksum == self.checksum)
+            .ok_or(format!("Unknown Btrfs checksum '{}'", self.checksum))?;
+        if progs < *checksum_version || target_kernel < *checksum_version {
+            return Err(format!(
+                "The '{}' checksum needs btrfs-progs and a kernel of at least {}.{}",
+                self.checksum, checksum_version.0, checksum_version.1
+            ));
+        }
+
+        let nodesize = self.nodesize.trim_end_matches(['k', 'K']).parse::<u32>().unwrap_or(0);
+        if ![4, 8, 16, 32, 64].contains(&nodesize) {
+            return Err(format!("Btrfs node size must be 4k to 64k, got '{}'", self.nodesize));
+        }
+
+        // Only one device, so there's nothing to mirror or stripe across.
+        if !["dup", "single"].contains(&self.metadata.as_str()) {
+            return Err(format!(
+                "Btrfs metadata profile must be dup or single, got '{}'",
+                self.metadata
+            ));
+        }
+
+        // mkfs.btrfs truncates longer labels.
+        if self.label.len() > 255 {
+            return Err("Btrfs labels can be at most 255 bytes".to_string());
+        }
+
+        for feature in &self.features {
+            let (_, sysfs_name, kernel_version) = FEATURES
+                .iter()
+                .find(|(name, _, _)| name == feature)
+                .ok_or(format!("Unknown Btrfs feature '{}'", feature))?;
+
+            if !progs_features.contains(feature.as_str()) {
+                return Err(format!(
+                    "The installed btrfs-progs can't create the '{}' feature",
+                    feature
+                ));
+            }
+            if !Path::new("/sys/fs/btrfs/features").join(sysfs_name).exists() {
+                return Err(format!("The running kernel can't mount the '{}' feature", feature));
+            }
+            if target_kernel < *kernel_version {
+                return Err(format!(
+                    "The '{}' feature needs a kernel of at least {}.{}, the target kernel is {}.{}",
+                    feature, kernel_version.0, kernel_version.1, target_kernel.0, target_kernel.1
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn mkfs_args(&self) -> Vec<String> {
+        let mut args = vec![
+            "--csum".to_string(),
+            self.checksum.clone(),
+            "--nodesize".to_string(),
+            self.nodesize.clone(),
+            "--label".to_string(),
+            self.label.clone(),
+            "--metadata".to_string(),
+            self.metadata.clone(),
+        ];
+
+        if !self.features.is_empty() {
+            args.push("--features".to_string());
+            args.push(self.features.join(","));
+        }
+
+        args
+    }
+
+    // The initramfs needs the checksum's crypto module to mount the root filesystem.
+    pub fn initramfs_modules(&self) -> Vec<&'static str> {
+        match self.checksum.as_str() {
+            "xxhash" => vec!["xxhash_generic"],
+            "sha256" => vec!["sha256"],
+            "blake2" => vec!["blake2b_generic"],
+            _ => vec![],
+        }
+    }
+}
@@ -7,6 +7,20 @@ use std::io::{self, BufRead, BufReader, Read, Write};
 use std::path::Path;
 use std::process::{Command, Output, Stdio};
 
+use layout::Subvolume;
+
+pub mod bootloader;
+pub mod cmdline;
+pub mod filesystem;
+pub mod fstab;
+pub mod initramfs;
+pub mod kernel;
+pub mod layout;
+pub mod luks;
+pub mod mkfs;
+pub mod plymouth;
+pub mod secure_boot;
+
 pub fn prompt(description: &str) -> String {
     print!("{description}");
 
@@ -55,10 +69,7 @@ pub fn run_shell_command(command: &str) -> std::io::Result<Output> {
                 command,
                 String::from_utf8_lossy(&output.stderr)
             );
-            Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Shell command execution failed",
-            ))
+            Err(std::io::Error::other("Shell command execution failed"))
         }
 }
 
@@ -78,10 +89,7 @@ pub fn run_command(command: &str, args: &[&str]) -> std::io::Result<Output> {
             command,
             String::from_utf8_lossy(&output.stderr)
         );
-        Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "Command execution failed",
-        ))
+        Err(std::io::Error::other("Command execution failed"))
     }
 }
 
@@ -150,13 +158,50 @@ pub fn fetch_disk() -> io::Result<String> {
     }
 }
 
-pub fn create_sub_volumes(subvol_list: &[String]) -> io::Result<()> {
-    for subvol in subvol_list {
-        let path = format!("/mnt/@{}", subvol);
-        if let Err(err) = run_command("btrfs", &["subvolume", "create", &path]) {
-            eprintln!("Failed to create subvolume {}: {}", subvol, err);
-        } else {
-            println!("Successfully created subvolume: {}", subvol);
+// NVMe and eMMC disks separate the partition number with a 'p', e.g. /dev/nvme0n1p3.
+pub fn partition_path(disk: &str, number: u8) -> String {
+    if disk.ends_with(|c: char| c.is_ascii_digit()) {
+        format!("{}p{}", disk, number)
+    } else {
+        format!("{}{}", disk, number)
+    }
+}
+
+// Expects the top-level subvolume (subvolid=5) to be mounted at /mnt.
+// Only a reinstall keeps existing subvolumes; otherwise one is left from an earlier install or run.
+pub fn create_sub_volumes(layout: &[Subvolume], reinstall: bool) -> io::Result<()> {
+    for subvol in layout {
+        let path = format!("/mnt/{}", subvol.name);
+        if Path::new(&path).exists() {
+            if reinstall {
+                println!("Keeping existing subvolume: {}", subvol.name);
+                continue;
+            }
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "Subvolume {} already exists; reformat the root or enable reinstall mode",
+                    subvol.name
+                ),
+            ));
+        }
+        run_command("btrfs", &["subvolume", "create", &path]).map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("Failed to create subvolume {}: {}", subvol.name, err),
+            )
+        })?;
+        println!("Successfully created subvolume: {}", subvol.name);
+
+        // Must be set while the subvolume is still empty; existing files keep copy-on-write.
+        if subvol.nocow {
+            run_command("chattr", &["+C", &path])?;
+
+            let attributes = run_command("lsattr", &["-d", &path])?;
+            let attributes = String::from_utf8_lossy(&attributes.stdout);
+            if !attributes.split_whitespace().next().unwrap_or("").contains('C') {
+                return Err(io::Error::other(format!("Failed to set NOCOW on subvolume {}", subvol.name)));
+            }
         }
     }
     Ok(())
@@ -188,24 +233,49 @@ pub fn config_write(value: &str, line: &str, file_path: &str) -> io::Result<()>
 }
 
 pub fn touch_file(path: &str) -> io::Result<()> {
-    match OpenOptions::new().create(true).write(true).open(path) {
+    match OpenOptions::new().create(true).truncate(false).write(true).open(path) {
         Ok(_) => Ok(()),
         Err(err) => Err(err),
     }
 }
 
-pub fn find_option(option: &str) -> Result<String, Box<dyn std::error::Error>> {
-    let file_path = "/root/arch-flux/user_selections.cfg";
+// Reads the value of a "key=value" line from one of the installer's config files.
+fn find_config_value(file_path: &str, option: &str) -> Result<String, Box<dyn std::error::Error>> {
     let file_contents = std::fs::read_to_string(file_path)?;
-    let re = regex::Regex::new(&format!(r"{}=(\w+)", option))?;
-    let layout = re
+    let re = regex::Regex::new(&format!(r"(?m)^{}=(\S+)", option))?;
+    let value = re
         .captures(&file_contents)
         .ok_or(format!("Failed to find {}", option))?
         .get(1)
         .ok_or(format!("Failed to extract {}", option))?
         .as_str()
         .to_string();
-    Ok(layout)
+    Ok(value)
+}
+
+pub fn find_option(option: &str) -> Result<String, Box<dyn std::error::Error>> {
+    find_config_value("/root/arch-flux/user_selections.cfg", option)
+}
+
+// "NVME", "SSD", "HDD" or "Unknown", as recorded by disk_format on the line after the disk.
+pub fn fetch_disk_type() -> String {
+    let contents = fs::read_to_string("/root/arch-flux/selected_disk.cfg").unwrap_or_default();
+    contents
+        .lines()
+        .nth(1)
+        .filter(|line| ["NVME", "SSD", "HDD"].contains(line))
+        .unwrap_or("Unknown")
+        .to_string()
+}
+
+// disk_format records "firmware=bios" if the live environment wasn't booted through UEFI.
+pub fn is_bios() -> bool {
+    find_disk_option("firmware").is_ok_and(|firmware| firmware == "bios")
+}
+
+// Options that disk_format records next to the selected disk, such as "integrity=hmac-sha256".
+pub fn find_disk_option(option: &str) -> Result<String, Box<dyn std::error::Error>> {
+    find_config_value("/root/arch-flux/selected_disk.cfg", option)
 }
 
 pub fn replace_text (path: &str, old: &str, new: &str) -> io::Result<()> {
@@ -217,7 +287,7 @@ pub fn replace_text (path: &str, old: &str, new: &str) -> io::Result<()> {
 
 pub fn get_march() -> Result<String, String> {
     let output = Command::new("gcc")
-        .args(&["-march=native", "-Q", "--help=target"])
+        .args(["-march=native", "-Q", "--help=target"])
         .output()
         .map_err(|e| e.to_string())?;
 
@@ -249,7 +319,7 @@ pub fn copy_recursively(src: &Path, dest: &Path) -> anyhow::Result<()> {
             if let Some(parent) = dest_path.parent() {
                 fs::create_dir_all(parent)?;
             }
-            fs::copy(&src_path, &dest_path)?;
+            fs::copy(src_path, &dest_path)?;
         }
     }
     Ok(())
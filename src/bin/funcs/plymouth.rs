@@ -0,0 +1,38 @@
+use super::config_write;
+use anyhow::{bail, Context};
+use std::fs;
+use std::path::Path;
+
+// The themes shipped with the plymouth package; bgrt shows the firmware's logo.
+pub const THEMES: [&str; 8] = [
+    "bgrt",
+    "spinner",
+    "fade-in",
+    "glow",
+    "solar",
+    "spinfinity",
+    "tribar",
+    "text",
+];
+
+const PLYMOUTHD_CONF: &str = "/etc/plymouth/plymouthd.conf";
+
+// The plymouth hook embeds the theme set here, so this has to run before the initramfs is built.
+pub fn configure_plymouth(theme: &str) -> anyhow::Result<()> {
+    let theme_file = format!("/usr/share/plymouth/themes/{0}/{0}.plymouth", theme);
+    if !Path::new(&theme_file).exists() {
+        bail!(
+            "The Plymouth theme '{}' isn't installed, {} is missing",
+            theme,
+            theme_file
+        );
+    }
+
+    fs::create_dir_all("/etc/plymouth")?;
+    fs::copy("/root/arch-flux/files/etc/plymouth/plymouthd.conf", PLYMOUTHD_CONF)
+        .with_context(|| format!("Failed to copy {}", PLYMOUTHD_CONF))?;
+    config_write(theme, "Theme=", PLYMOUTHD_CONF).with_context(|| format!("Failed to write {}", PLYMOUTHD_CONF))?;
+
+    println!("Set the Plymouth theme to {}", theme);
+    Ok(())
+}
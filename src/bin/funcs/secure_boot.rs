@@ -0,0 +1,64 @@
+use super::run_command;
+use anyhow::{bail, Context};
+use regex::Regex;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+
+fn sbctl_status(field: &str) -> anyhow::Result<bool> {
+    let output = run_command("sbctl", &["status", "--json"]).with_context(|| "Failed to read sbctl's status")?;
+    let re = Regex::new(&format!(r#""{}":\s*true"#, field))?;
+    Ok(re.is_match(&String::from_utf8_lossy(&output.stdout)))
+}
+
+// Creates our own keys, optionally enrolls them, then signs everything that boots from the ESP.
+// Enrolling only works in setup mode; otherwise the keys have to be enrolled from the firmware setup later.
+pub fn configure_secure_boot(enroll: bool, microsoft_keys: bool) -> anyhow::Result<()> {
+    if !sbctl_status("installed")? {
+        run_command("sbctl", &["create-keys"]).with_context(|| "Failed to create the Secure Boot keys")?;
+        println!("Created the Secure Boot keys");
+    }
+
+    if enroll {
+        if sbctl_status("setup_mode")? {
+            // Some firmware and option ROMs (e.g. GPUs) are signed with Microsoft's keys only.
+            let mut args = vec!["enroll-keys"];
+            if microsoft_keys {
+                args.push("--microsoft");
+            }
+            run_command("sbctl", &args).with_context(|| "Failed to enroll the Secure Boot keys")?;
+            println!("Enrolled the Secure Boot keys");
+        } else {
+            eprintln!("The firmware isn't in Secure Boot setup mode, enroll the keys with 'sbctl enroll-keys' later");
+        }
+    }
+
+    let files = [
+        "/usr/local/bin/secure-boot-sign",
+        "/etc/pacman.d/hooks/zz-secure-boot-sign.hook",
+    ];
+    for file in files {
+        fs::copy(format!("/root/arch-flux/files{}", file), file).with_context(|| format!("Failed to copy {}", file))?;
+    }
+    fs::set_permissions("/usr/local/bin/secure-boot-sign", fs::Permissions::from_mode(0o755))?;
+
+    run_command("/usr/local/bin/secure-boot-sign", &[]).with_context(|| "Failed to sign the EFI binaries")?;
+
+    verify_secure_boot()
+}
+
+// sbctl verify checks every EFI binary on the ESP, not only those in its database.
+pub fn verify_secure_boot() -> anyhow::Result<()> {
+    let output = run_command("sbctl", &["verify"]).with_context(|| "Failed to verify the EFI binaries")?;
+    let output = String::from_utf8_lossy(&output.stdout);
+
+    let unsigned: Vec<&str> = output.lines().filter(|line| line.contains("is not signed")).collect();
+    if !unsigned.is_empty() {
+        for line in &unsigned {
+            eprintln!("{}", line.trim());
+        }
+        bail!("{} EFI binaries on the ESP aren't signed", unsigned.len());
+    }
+
+    println!("Verified that every EFI binary on the ESP is signed");
+    Ok(())
+}
@@ -1,13 +1,21 @@
 use dialoguer::theme::ColorfulTheme;
-use dialoguer::{Confirm, FuzzySelect, Input, Select};
+use dialoguer::{Confirm, FuzzySelect, Input, MultiSelect, Select};
+use funcs::filesystem::{Filesystem, Storage};
+use funcs::fstab::{blkid_value, build_crypttab, build_fstab, render_fstab, verify_fstab, FstabSources};
+use funcs::kernel::{Kernels, DKMS_MODULES, KERNELS};
+use funcs::layout::{compression_mount_option, load_layout, Subvolume};
+use funcs::luks::open_luks_container;
+use funcs::mkfs::{target_kernel_version, BtrfsFormat};
+use funcs::plymouth::THEMES;
 use funcs::{
-    archiso_check, config_write, copy_recursively, create_sub_volumes, fetch_disk, run_command, run_shell_command,
+    archiso_check, config_write, copy_recursively, create_sub_volumes, fetch_disk, fetch_disk_type, find_option,
+    partition_path, run_command, run_shell_command,
 };
 use regex::Regex;
 use std::path::Path;
 use std::{
     fs,
-    process::{self, Command, Stdio},
+    process::{self, Command},
 };
 
 mod funcs;
@@ -27,47 +35,75 @@ fn checks() -> std::io::Result<String> {
         }
     }
 
-    let cryptsetup_status = Command::new("cryptsetup")
-        .args(["status", "arch"])
-        .stdout(Stdio::piped()) // To pipe the output to another command
-        .spawn()
-        .expect("Failed to start cryptsetup command");
-
-    let grep_result = Command::new("grep")
-        .args(["-q", "inactive"])
-        .stdin(cryptsetup_status.stdout.expect("Failed to get stdout of cryptsetup"))
-        .status()
-        .expect("Failed to run grep command");
-
-    if grep_result.success() {
-        eprintln!("\nERROR: Forgot to mount the LUKS2 partition as the name 'root'?\n");
-        process::exit(1);
+    if let Ok(ref disk) = disk {
+        if let Err(e) = open_luks_container(disk) {
+            eprintln!("\nERROR: Failed to open the LUKS2 container: {}\n", e);
+            process::exit(1);
+        }
     }
     disk // Return result directly
 }
 
-fn create_and_mount_filesystems(disk: &str) -> std::io::Result<()> {
-    let location = "/dev/mapper/arch";
-    let boot_part = format!("{}1", disk);
-
-    let subvol_list: Vec<String> = "root btrfs srv snapshots pkg log home"
-        .split(' ')
-        .map(String::from)
-        .collect();
-    let subvol_mount_list: Vec<String> = "root btrfs srv pkg log home".split(' ').map(String::from).collect();
-
-    // Check if there's already a Btrfs file system
-    if !Command::new("lsblk")
-        .args(&["-o", "FSTYPE", &location])
+fn create_and_mount_filesystems(
+    disk: &str,
+    filesystem: Filesystem,
+    storage: Storage,
+    layout: &[Subvolume],
+    fs_compression: &str,
+    btrfs_format: &BtrfsFormat,
+    reinstall: bool,
+) -> std::io::Result<()> {
+    let boot_part = partition_path(disk, 1);
+
+    let lvm_root_size = find_option("lvm_root_size").unwrap_or("64G".to_string());
+    storage.create_volumes(&lvm_root_size)?;
+    let location = storage.root_device();
+
+    // Check if the root volume already has the selected file system
+    let fstype = Command::new("lsblk")
+        .args(["-no", "FSTYPE", &location])
         .output()?
-        .stdout
-        .windows(5)
-        .any(|window| window == b"btrfs")
-    {
-        run_command("mkfs.btrfs", &[&location])?;
-        run_command("mkfs.fat", &["-F", "32", &boot_part])?;
+        .stdout;
+    let has_filesystem = String::from_utf8_lossy(&fstype).trim() == filesystem.name();
+
+    if reinstall {
+        if !has_filesystem {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!(
+                    "Nothing to reinstall, {} has no {} filesystem",
+                    location,
+                    filesystem.name()
+                ),
+            ));
+        }
+        // Without subvolumes, only a separate home volume can survive reformatting the root.
+        if filesystem != Filesystem::Btrfs && storage.home_device().is_none() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!("Reinstalling on {} needs the LVM storage layout", filesystem.name()),
+            ));
+        }
+
+        // The ESP only holds the previous install's kernels and bootloader.
+        run_command("mkfs.fat", &["-F", "32", "-n", "BOOTEFI", &boot_part])?;
+        if filesystem != Filesystem::Btrfs {
+            filesystem.mkfs(&location, "arch", btrfs_format)?;
+        }
+    } else if !has_filesystem {
+        let root_label = match filesystem {
+            Filesystem::Btrfs => btrfs_format.label.as_str(),
+            _ => "arch",
+        };
+        filesystem.mkfs(&location, root_label, btrfs_format)?;
+        if let Some(home) = storage.home_device() {
+            filesystem.mkfs(&home, "home", btrfs_format)?;
+        }
+        if let Some(swap) = storage.swap_device() {
+            run_command("mkswap", &["-L", "swap", &swap])?;
+        }
+        run_command("mkfs.fat", &["-F", "32", "-n", "BOOTEFI", &boot_part])?;
     }
-    let opts = format!("defaults,noatime,compress=zstd:1");
 
     let _ = fs::create_dir("/mnt");
     let _ = run_command("umount", &["-flR", "/mnt"]);
@@ -75,55 +111,119 @@ fn create_and_mount_filesystems(disk: &str) -> std::io::Result<()> {
     fs::remove_dir_all("/mnt")?;
     fs::create_dir("/mnt")?;
 
-    let root_mount = format!("mount -t btrfs -o {} {} /mnt", &opts, &location);
-    if let Err(e) = run_shell_command(&root_mount) {
-        eprintln!("Failed to mount root: {}", e);
-        process::exit(1);
+    if filesystem != Filesystem::Btrfs {
+        run_command(
+            "mount",
+            &[
+                "-t",
+                filesystem.name(),
+                "-o",
+                &filesystem.mount_options(fs_compression),
+                &location,
+                "/mnt",
+            ],
+        )?;
+        println!("Mounted the {} root filesystem", filesystem.name());
+    } else {
+        mount_sub_volumes(&location, layout, fs_compression, reinstall)?;
     }
 
-    // Must be ran after btrfs -> /mnt is mounted
-    let base_path = "/mnt";
-    // Has to be this specific order, otherwise it will fail in the for(subvol, dir) loop
-    let directories = [
-        "root",
-        "btrfs",
-        "srv",
-        "var/cache/pacman/pkg",
-        "var/log",
-        "home",
-        // The following below might not be required after running pacstrap
-        "tmp",
-        "boot",
-        "proc",
-        "sys",
-        "dev",
-        "run",
-        "etc",
-    ];
-
-    for dir in directories.iter() {
-        let full_path = format!("{}/{}", base_path, dir);
-        match fs::create_dir_all(&full_path) {
-            Ok(_) => println!("Created directory: {}", full_path),
-            Err(e) => println!("Failed to create directory '{}': {}", full_path, e),
-        }
+    if let Some(home) = storage.home_device() {
+        fs::create_dir_all("/mnt/home")?;
+        run_command(
+            "mount",
+            &[
+                "-t",
+                filesystem.name(),
+                "-o",
+                &filesystem.mount_options(fs_compression),
+                &home,
+                "/mnt/home",
+            ],
+        )?;
+        println!("Mounted the home volume");
     }
 
-    create_sub_volumes(&subvol_list)?;
-
-    let boot_mount = format!("-t vfat -o nodev,nosuid,noexec {} /mnt/boot", &boot_part);
-    run_command("mount", &[&boot_mount])?;
+    fs::create_dir_all("/mnt/boot")?;
+    run_command(
+        "mount",
+        &["-t", "vfat", "-o", "nodev,nosuid,noexec", &boot_part, "/mnt/boot"],
+    )?;
     println!("Mounted boot partition");
 
-    let btrfs_mount = format!("-t btrfs -o {},subvolid=5 {} /mnt/btrfs", &location, &opts);
-    run_command("mount", &[&btrfs_mount])?;
-    println!("Mounted btrfs subvolume");
+    Ok(())
+}
+
+// Moves every subvolume that isn't kept aside as <name>.old-<date>, or deletes it.
+// Expects the top-level subvolume (subvolid=5) to be mounted at /mnt.
+fn clear_sub_volumes(layout: &[Subvolume]) -> std::io::Result<()> {
+    let keep = find_option("reinstall_keep").unwrap_or("@home".to_string());
+    let keep: Vec<&str> = keep.split(',').collect();
+    let delete = find_option("reinstall_old").unwrap_or("keep".to_string()) == "delete";
+
+    let timestamp_output = run_command("date", &["+%Y%m%d-%H%M%S"])?;
+    let timestamp = String::from_utf8_lossy(&timestamp_output.stdout).trim().to_string();
+
+    for subvol in layout.iter().filter(|subvol| !keep.contains(&subvol.name.as_str())) {
+        let path = format!("/mnt/{}", subvol.name);
+        if !Path::new(&path).exists() {
+            continue;
+        }
 
-    for (subvol, dir) in subvol_mount_list.iter().zip(directories.iter()) {
-        let full_path = format!("{}/{}", base_path, dir);
-        let subvol_mount = format!("-t btrfs -o {} subvol=@{} {} {}", &opts, &subvol, &location, &full_path);
-        run_command("mount", &[&subvol_mount])?;
-        println!("Mounted subvolume: {}", subvol);
+        if delete {
+            run_command("btrfs", &["subvolume", "delete", "--recursive", &path])?;
+            println!("Deleted subvolume {}", subvol.name);
+        } else {
+            // Nested subvolumes, such as snapper's snapshots, move along with it.
+            let old_name = format!("{}.old-{}", subvol.name, timestamp);
+            fs::rename(&path, format!("/mnt/{}", old_name))?;
+            println!("Moved subvolume {} to {}", subvol.name, old_name);
+        }
+    }
+
+    Ok(())
+}
+
+fn mount_sub_volumes(
+    location: &str,
+    layout: &[Subvolume],
+    fs_compression: &str,
+    reinstall: bool,
+) -> std::io::Result<()> {
+    // Subvolumes are created from the top-level subvolume, which isn't mounted in the installed system.
+    if let Err(e) = run_command("mount", &["-t", "btrfs", "-o", "subvolid=5", location, "/mnt"]) {
+        eprintln!("Failed to mount the top-level subvolume: {}", e);
+        process::exit(1);
+    }
+    if reinstall {
+        clear_sub_volumes(layout)?;
+    }
+    create_sub_volumes(layout, reinstall)?;
+    run_command("umount", &["/mnt"])?;
+
+    // The layout is sorted by mountpoint depth, so / is mounted first.
+    for subvol in layout {
+        let full_path = format!("/mnt{}", subvol.mountpoint);
+        fs::create_dir_all(&full_path)?;
+
+        run_command(
+            "mount",
+            &[
+                "-t",
+                "btrfs",
+                "-o",
+                &subvol.mount_options(fs_compression),
+                location,
+                &full_path,
+            ],
+        )?;
+        println!("Mounted subvolume {} at {}", subvol.name, subvol.mountpoint);
+
+        // NOCOW files are never compressed, so there's nothing to override.
+        if let Some(compression) = subvol.compression_property().filter(|_| !subvol.nocow) {
+            run_command("btrfs", &["property", "set", &full_path, "compression", compression])?;
+            println!("Set compression of subvolume {} to {}", subvol.name, compression);
+        }
     }
 
     Ok(())
@@ -139,8 +239,35 @@ gpu_selected=1
 nvidia_stream_memory_operations=false
 intel_video_accel=0
 no_mitigations=false
+hibernation=false
+boot_splash=true
+plymouth_theme=bgrt
 printers_and_scanners=true
-hardware_wifi_and_bluetooth=true\n";
+hardware_wifi_and_bluetooth=true
+tpm2_unlock=false
+tpm2_pcrs=7
+snapshots=true
+compression_preset=auto
+filesystem=btrfs
+kernels=linux,linux-lts
+default_kernel=linux
+dkms_modules=
+bootloader=grub
+ukis=false
+uki_fallback=false
+efi_fallback=true
+secure_boot=false
+secure_boot_enroll=true
+secure_boot_microsoft_keys=true
+lvm_root_size=64G
+btrfs_checksum=crc32c
+btrfs_nodesize=16k
+btrfs_label=arch
+btrfs_metadata=dup
+btrfs_features=free-space-tree,block-group-tree
+reinstall=false
+reinstall_keep=@home
+reinstall_old=keep\n";
         std::fs::write("/root/arch-flux/user_selections.cfg", contents)?;
     }
     let items = vec![
@@ -152,8 +279,24 @@ hardware_wifi_and_bluetooth=true\n";
         "nvidia_stream_memory_operations",
         "Configure Intel GPU video acceleration",
         "Disable all CPU mitigations",
+        "Hibernation (LVM storage layout only)",
+        "Boot splash (Plymouth)",
         "Printer and Scanner support",
         "Wi-Fi and Bluetooth support",
+        "TPM2 unlock of the LUKS2 container",
+        "TPM2 PCRs to bind to",
+        "Btrfs snapshots (snapper)",
+        "Root filesystem",
+        "LVM root volume size",
+        "Kernels",
+        "DKMS modules",
+        "Bootloader",
+        "Unified kernel images (UKIs)",
+        "Removable EFI fallback (EFI/BOOT/BOOTX64.EFI)",
+        "Secure Boot (sbctl)",
+        "Btrfs compression preset",
+        "Btrfs format options",
+        "Reinstall, keeping existing subvolumes",
         "Continue / Exit",
     ];
 
@@ -179,12 +322,8 @@ hardware_wifi_and_bluetooth=true\n";
 
             let keyboard_layout = &items[keyboard_layout_index];
 
-            let line = format!("keyboard_layout=");
-            config_write(
-                &keyboard_layout.to_string(),
-                &line,
-                "/root/arch-flux/user_selections.cfg",
-            )?;
+            let line = "keyboard_layout=";
+            config_write(keyboard_layout, line, "/root/arch-flux/user_selections.cfg")?;
         }
         "Username" => {
             let username = Input::<String>::with_theme(&theme)
@@ -192,8 +331,8 @@ hardware_wifi_and_bluetooth=true\n";
                 .interact()
                 .unwrap();
 
-            let line = format!("username=");
-            config_write(&username.to_string(), &line, "/root/arch-flux/user_selections.cfg")?;
+            let line = "username=";
+            config_write(&username.to_string(), line, "/root/arch-flux/user_selections.cfg")?;
         }
         "Password" => {
             let password = Input::<String>::with_theme(&theme)
@@ -201,8 +340,8 @@ hardware_wifi_and_bluetooth=true\n";
                 .interact()
                 .unwrap();
 
-            let line = format!("password=");
-            config_write(&password.to_string(), &line, "/root/arch-flux/user_selections.cfg")?;
+            let line = "password=";
+            config_write(&password.to_string(), line, "/root/arch-flux/user_selections.cfg")?;
         }
         "Hostname" => {
             let hostname = Input::<String>::with_theme(&theme)
@@ -210,8 +349,8 @@ hardware_wifi_and_bluetooth=true\n";
                 .interact()
                 .unwrap();
 
-            let line = format!("hostname=");
-            config_write(&hostname.to_string(), &line, "/root/arch-flux/user_selections.cfg")?;
+            let line = "hostname=";
+            config_write(&hostname.to_string(), line, "/root/arch-flux/user_selections.cfg")?;
         }
         "Select GPU type to install drivers for" => {
             let gpu_selected = Select::with_theme(&theme)
@@ -221,8 +360,8 @@ hardware_wifi_and_bluetooth=true\n";
                 .interact()
                 .unwrap();
 
-            let line = format!("gpu_selected=");
-            config_write(&gpu_selected.to_string(), &line, "/root/arch-flux/user_selections.cfg")?;
+            let line = "gpu_selected=";
+            config_write(&gpu_selected.to_string(), line, "/root/arch-flux/user_selections.cfg")?;
         }
         "nvidia_stream_memory_operations" => {
             let nvidia_stream_memory_operations = Confirm::with_theme(&theme)
@@ -230,10 +369,10 @@ hardware_wifi_and_bluetooth=true\n";
                 .interact()
                 .unwrap();
 
-            let line = format!("nvidia_stream_memory_operations=");
+            let line = "nvidia_stream_memory_operations=";
             config_write(
                 &nvidia_stream_memory_operations.to_string(),
-                &line,
+                line,
                 "/root/arch-flux/user_selections.cfg",
             )?;
         }
@@ -249,36 +388,64 @@ hardware_wifi_and_bluetooth=true\n";
                 .interact()
                 .unwrap();
 
-            let line = format!("intel_video_accel=");
+            let line = "intel_video_accel=";
             config_write(
                 &intel_video_accel.to_string(),
-                &line,
+                line,
                 "/root/arch-flux/user_selections.cfg",
             )?;
         }
-        "Disable all CPU mitigations" => {
-            let no_mitigations = Confirm::with_theme(&theme)
-                .with_prompt("Disable all CPU mitigations?")
+        "Hibernation (LVM storage layout only)" => {
+            let hibernation = Confirm::with_theme(&theme)
+                .with_prompt("Resume from the swap volume after hibernating?")
                 .interact()
                 .unwrap();
 
-            let line = format!("no_mitigations=");
             config_write(
-                &no_mitigations.to_string(),
-                &line,
+                &hibernation.to_string(),
+                "hibernation=",
                 "/root/arch-flux/user_selections.cfg",
             )?;
         }
+        "Boot splash (Plymouth)" => {
+            let boot_splash = Confirm::with_theme(&theme)
+                .with_prompt("Show a boot splash, which also asks for the LUKS2 passphrase?")
+                .default(true)
+                .interact()
+                .unwrap();
+
+            let file_path = "/root/arch-flux/user_selections.cfg";
+            config_write(&boot_splash.to_string(), "boot_splash=", file_path)?;
+
+            if boot_splash {
+                let plymouth_theme = Select::with_theme(&theme)
+                    .with_prompt("Select a Plymouth theme (bgrt shows the firmware's logo)")
+                    .default(0)
+                    .items(&THEMES)
+                    .interact()
+                    .unwrap();
+                config_write(THEMES[plymouth_theme], "plymouth_theme=", file_path)?;
+            }
+        }
+        "Disable all CPU mitigations" => {
+            let no_mitigations = Confirm::with_theme(&theme)
+                .with_prompt("Disable all CPU mitigations?")
+                .interact()
+                .unwrap();
+
+            let line = "no_mitigations=";
+            config_write(&no_mitigations.to_string(), line, "/root/arch-flux/user_selections.cfg")?;
+        }
         "Printer and Scanner support" => {
             let printers_and_scanners = Confirm::with_theme(&theme)
                 .with_prompt("Install printer and scanner drivers?")
                 .interact()
                 .unwrap();
 
-            let line = format!("printers_and_scanners=");
+            let line = "printers_and_scanners=";
             config_write(
                 &printers_and_scanners.to_string(),
-                &line,
+                line,
                 "/root/arch-flux/user_selections.cfg",
             )?;
         }
@@ -288,13 +455,295 @@ hardware_wifi_and_bluetooth=true\n";
                 .interact()
                 .unwrap();
 
-            let line = format!("hardware_wifi_and_bluetooth=");
+            let line = "hardware_wifi_and_bluetooth=";
             config_write(
                 &wifi_and_bluetooth.to_string(),
-                &line,
+                line,
+                "/root/arch-flux/user_selections.cfg",
+            )?;
+        }
+        "TPM2 unlock of the LUKS2 container" => {
+            let tpm2_unlock = Confirm::with_theme(&theme)
+                .with_prompt("Unlock the LUKS2 container with the TPM2? The passphrase stays as a fallback.")
+                .interact()
+                .unwrap();
+
+            let line = "tpm2_unlock=";
+            config_write(&tpm2_unlock.to_string(), line, "/root/arch-flux/user_selections.cfg")?;
+        }
+        "TPM2 PCRs to bind to" => {
+            let tpm2_pcrs = Input::<String>::with_theme(&theme)
+                .with_prompt("\nEnter the PCRs joined by '+', e.g. 7 or 0+7")
+                .default("7".to_string())
+                .validate_with(|input: &String| -> Result<(), &str> {
+                    if input.split('+').all(|pcr| pcr.parse::<u8>().is_ok_and(|pcr| pcr < 24)) {
+                        Ok(())
+                    } else {
+                        Err("PCRs must be numbers from 0 to 23 joined by '+'")
+                    }
+                })
+                .interact()
+                .unwrap();
+
+            let line = "tpm2_pcrs=";
+            config_write(&tpm2_pcrs, line, "/root/arch-flux/user_selections.cfg")?;
+        }
+        "Btrfs snapshots (snapper)" => {
+            let snapshots = Confirm::with_theme(&theme)
+                .with_prompt("Enable snapper snapshots, including before and after pacman transactions?")
+                .interact()
+                .unwrap();
+
+            let line = "snapshots=";
+            config_write(&snapshots.to_string(), line, "/root/arch-flux/user_selections.cfg")?;
+        }
+        "Root filesystem" => {
+            let filesystems = Filesystem::ALL.map(Filesystem::name);
+            let filesystem = Select::with_theme(&theme)
+                .with_prompt("Select the root filesystem (snapshots and subvolumes need Btrfs)")
+                .default(0)
+                .items(&filesystems)
+                .interact()
+                .unwrap();
+
+            config_write(
+                filesystems[filesystem],
+                "filesystem=",
+                "/root/arch-flux/user_selections.cfg",
+            )?;
+        }
+        "LVM root volume size" => {
+            let size_regex = Regex::new(r"^\d+[MGT]$").unwrap();
+            let lvm_root_size = Input::<String>::with_theme(&theme)
+                .with_prompt("\nEnter the root volume's size, e.g. 64G; the home volume gets the rest")
+                .default("64G".to_string())
+                .validate_with(|input: &String| -> Result<(), &str> {
+                    if size_regex.is_match(input) {
+                        Ok(())
+                    } else {
+                        Err("Enter a number followed by M, G or T")
+                    }
+                })
+                .interact()
+                .unwrap();
+
+            config_write(&lvm_root_size, "lvm_root_size=", "/root/arch-flux/user_selections.cfg")?;
+        }
+        "Kernels" => {
+            let selected = MultiSelect::with_theme(&theme)
+                .with_prompt("Select the kernels to install (space to toggle)")
+                .items(&KERNELS)
+                .defaults(&[true, true, false, false])
+                .interact()
+                .unwrap();
+            if selected.is_empty() {
+                println!("\nNOTICE: Select at least one kernel.\n");
+            } else {
+                let selected: Vec<&str> = selected.into_iter().map(|index| KERNELS[index]).collect();
+
+                let default_kernel = Select::with_theme(&theme)
+                    .with_prompt("Select the kernel to boot by default")
+                    .default(0)
+                    .items(&selected)
+                    .interact()
+                    .unwrap();
+
+                let file_path = "/root/arch-flux/user_selections.cfg";
+                config_write(&selected.join(","), "kernels=", file_path)?;
+                config_write(selected[default_kernel], "default_kernel=", file_path)?;
+            }
+        }
+        "DKMS modules" => {
+            let items = vec![
+                "nvidia: NVIDIA's open kernel modules (nvidia-open-dkms)",
+                "virtualbox: VirtualBox host modules (virtualbox-host-dkms)",
+            ];
+            let selected = MultiSelect::with_theme(&theme)
+                .with_prompt("Select the out-of-tree modules to build for every kernel (space to toggle)")
+                .items(&items)
+                .interact()
+                .unwrap();
+            let selected: Vec<&str> = selected.into_iter().map(|index| DKMS_MODULES[index].0).collect();
+
+            config_write(
+                &selected.join(","),
+                "dkms_modules=",
+                "/root/arch-flux/user_selections.cfg",
+            )?;
+        }
+        "Bootloader" => {
+            let bootloaders = ["grub", "systemd-boot"];
+            let items = vec![
+                "grub: boots snapshots through grub-btrfs, and other operating systems through os-prober",
+                "systemd-boot: simpler, with an entry per installed kernel",
+            ];
+            let bootloader = Select::with_theme(&theme)
+                .with_prompt("Select a bootloader")
+                .default(0)
+                .items(&items)
+                .interact()
+                .unwrap();
+
+            config_write(
+                bootloaders[bootloader],
+                "bootloader=",
                 "/root/arch-flux/user_selections.cfg",
             )?;
         }
+        "Unified kernel images (UKIs)" => {
+            let ukis = Confirm::with_theme(&theme)
+                .with_prompt(
+                    "Build UKIs, booted by systemd-boot or directly by the firmware if GRUB is the bootloader?",
+                )
+                .interact()
+                .unwrap();
+
+            let file_path = "/root/arch-flux/user_selections.cfg";
+            config_write(&ukis.to_string(), "ukis=", file_path)?;
+
+            if ukis {
+                println!(
+                    "\nNOTICE: With UKIs, snapshots can only be booted from systemd-boot, and not under Secure Boot.\n"
+                );
+                let uki_fallback = Confirm::with_theme(&theme)
+                    .with_prompt("Also build fallback UKIs? Each takes up about 100 MiB of the 1 GiB ESP")
+                    .interact()
+                    .unwrap();
+                config_write(&uki_fallback.to_string(), "uki_fallback=", file_path)?;
+            }
+        }
+        "Removable EFI fallback (EFI/BOOT/BOOTX64.EFI)" => {
+            let efi_fallback = Confirm::with_theme(&theme)
+                .with_prompt("Also install the bootloader to EFI/BOOT, for firmware that forgets its boot entries?")
+                .default(true)
+                .interact()
+                .unwrap();
+
+            config_write(
+                &efi_fallback.to_string(),
+                "efi_fallback=",
+                "/root/arch-flux/user_selections.cfg",
+            )?;
+        }
+        "Secure Boot (sbctl)" => {
+            let secure_boot = Confirm::with_theme(&theme)
+                .with_prompt("Sign the bootloader and kernels with your own Secure Boot keys?")
+                .interact()
+                .unwrap();
+
+            let file_path = "/root/arch-flux/user_selections.cfg";
+            config_write(&secure_boot.to_string(), "secure_boot=", file_path)?;
+
+            if secure_boot {
+                let enroll = Confirm::with_theme(&theme)
+                    .with_prompt("Enroll the keys now? This needs the firmware to be in setup mode")
+                    .default(true)
+                    .interact()
+                    .unwrap();
+                let microsoft_keys = Confirm::with_theme(&theme)
+                    .with_prompt("Keep Microsoft's keys? Without them, firmware drivers such as a GPU's may not load")
+                    .default(true)
+                    .interact()
+                    .unwrap();
+
+                config_write(&enroll.to_string(), "secure_boot_enroll=", file_path)?;
+                config_write(&microsoft_keys.to_string(), "secure_boot_microsoft_keys=", file_path)?;
+            }
+        }
+        "Btrfs compression preset" => {
+            let presets = ["auto", "nvme", "sata", "hdd", "none"];
+            let items = vec![
+                "auto: pick from the disk type",
+                "nvme: compress=zstd:1, fast disks are barely held back by compression",
+                "sata: compress=zstd:3, for SATA SSDs",
+                "hdd: compress=zstd:3, slow disks gain the most from smaller writes",
+                "none: no compression, except for subvolumes that set it in subvolumes.cfg",
+            ];
+            let compression_preset = Select::with_theme(&theme)
+                .with_prompt("Select a Btrfs compression preset")
+                .default(0)
+                .items(&items)
+                .interact()
+                .unwrap();
+
+            let line = "compression_preset=";
+            config_write(presets[compression_preset], line, "/root/arch-flux/user_selections.cfg")?;
+        }
+        "Btrfs format options" => {
+            let checksums = ["crc32c", "xxhash", "blake2", "sha256"];
+            let checksum = Select::with_theme(&theme)
+                .with_prompt("Select the Btrfs checksum algorithm (crc32c and xxhash are the fastest)")
+                .default(0)
+                .items(&checksums)
+                .interact()
+                .unwrap();
+
+            let nodesizes = ["16k", "4k", "8k", "32k", "64k"];
+            let nodesize = Select::with_theme(&theme)
+                .with_prompt("Select the Btrfs node size")
+                .default(0)
+                .items(&nodesizes)
+                .interact()
+                .unwrap();
+
+            let label = Input::<String>::with_theme(&theme)
+                .with_prompt("\nEnter the Btrfs filesystem label")
+                .default("arch".to_string())
+                .interact()
+                .unwrap();
+
+            let metadata_profiles = ["dup", "single"];
+            let metadata = Select::with_theme(&theme)
+                .with_prompt("Select the Btrfs metadata profile (dup keeps two copies)")
+                .default(0)
+                .items(&metadata_profiles)
+                .interact()
+                .unwrap();
+
+            let features = Input::<String>::with_theme(&theme)
+                .with_prompt("\nEnter the mkfs.btrfs features, joined by ',' or 'none'")
+                .default("free-space-tree,block-group-tree".to_string())
+                .interact()
+                .unwrap();
+
+            let file_path = "/root/arch-flux/user_selections.cfg";
+            config_write(checksums[checksum], "btrfs_checksum=", file_path)?;
+            config_write(nodesizes[nodesize], "btrfs_nodesize=", file_path)?;
+            config_write(&label.replace(' ', "_"), "btrfs_label=", file_path)?;
+            config_write(metadata_profiles[metadata], "btrfs_metadata=", file_path)?;
+            config_write(&features, "btrfs_features=", file_path)?;
+        }
+        "Reinstall, keeping existing subvolumes" => {
+            let reinstall = Confirm::with_theme(&theme)
+                .with_prompt("Reinstall over the existing filesystem instead of formatting it?")
+                .interact()
+                .unwrap();
+
+            let file_path = "/root/arch-flux/user_selections.cfg";
+            config_write(&reinstall.to_string(), "reinstall=", file_path)?;
+
+            if reinstall {
+                let keep = Input::<String>::with_theme(&theme)
+                    .with_prompt("\nEnter the subvolumes to keep, joined by ','")
+                    .default(find_option("reinstall_keep").unwrap_or("@home".to_string()))
+                    .interact()
+                    .unwrap();
+
+                let old_choices = ["keep", "delete"];
+                let old = Select::with_theme(&theme)
+                    .with_prompt("What happens to the other subvolumes?")
+                    .default(0)
+                    .items(&[
+                        "keep: move them aside as <name>.old-<date>",
+                        "delete: remove them and their nested subvolumes",
+                    ])
+                    .interact()
+                    .unwrap();
+
+                config_write(&keep.replace(' ', ""), "reinstall_keep=", file_path)?;
+                config_write(old_choices[old], "reinstall_old=", file_path)?;
+            }
+        }
         "Continue / Exit" => {
             return Ok(());
         }
@@ -349,7 +798,38 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             return Err(Box::new(e));
         }
     }
-    if let Err(e) = create_and_mount_filesystems(disk_str) {
+    let filesystem = Filesystem::from_options();
+    let storage = Storage::from_disk_options();
+    let layout = storage.subvolumes(&load_layout()?);
+    let compression_preset = find_option("compression_preset").unwrap_or("auto".to_string());
+    let fs_compression = compression_mount_option(&compression_preset, &fetch_disk_type());
+
+    let btrfs_format = BtrfsFormat::from_options();
+    if filesystem == Filesystem::Btrfs {
+        // The oldest selected kernel has to be able to mount it.
+        let kernels = Kernels::from_options();
+        let target_kernel = kernels
+            .selected
+            .iter()
+            .map(|kernel| target_kernel_version(kernel))
+            .min()
+            .unwrap_or((0, 0));
+        if let Err(e) = btrfs_format.validate(target_kernel) {
+            eprintln!("Invalid Btrfs format options: {}", e);
+            process::exit(1);
+        }
+    }
+
+    let reinstall = find_option("reinstall").unwrap_or("false".to_string()) == "true";
+    if let Err(e) = create_and_mount_filesystems(
+        disk_str,
+        filesystem,
+        storage,
+        &layout,
+        fs_compression,
+        &btrfs_format,
+        reinstall,
+    ) {
         eprintln!("create_and_mount_filesystems failed: {}", e);
         return Err(Box::new(e));
     }
@@ -357,16 +837,25 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Account for Pacman suddenly exiting (due to the user sending SIGINT by pressing Ctrl + C).
     let _ = fs::remove_file("/mnt/var/lib/pacman/db.lck");
 
-    run_shell_command("pacstrap -K /mnt cryptsetup dosfstools btrfs-progs base base-devel git zsh grml-zsh-config reflector --noconfirm --ask=4 --needed")?;
+    let lvm_package = if storage == Storage::Lvm { " lvm2" } else { "" };
+    run_shell_command(&format!("pacstrap -K /mnt cryptsetup dosfstools {}{} base base-devel git zsh grml-zsh-config reflector --noconfirm --ask=4 --needed", filesystem.package(), lvm_package))?;
 
     pacman_mods()?;
 
-    let fstab_content = "# Static information about the filesystems.\n\
-                        # See fstab(5) for details.\n\
-                        \n\
-                        # <file system> <dir> <type> <options> <dump> <pass>\n";
-    fs::write("/mnt/etc/fstab", fstab_content)?;
-    run_shell_command("genfstab -U /mnt >>/mnt/etc/fstab")?;
+    // Built from the layout instead of genfstab, which lists whatever happens to be mounted under /mnt.
+    let fstab_sources = FstabSources {
+        root_uuid: blkid_value(&storage.root_device(), "UUID")?,
+        home_uuid: storage
+            .home_device()
+            .map(|home| blkid_value(&home, "UUID"))
+            .transpose()?,
+        esp_uuid: blkid_value(&partition_path(disk_str, 1), "UUID")?,
+        swap_partuuid: blkid_value(&partition_path(disk_str, 2), "PARTUUID").ok(),
+        swap_device: storage.swap_device(),
+    };
+    let fstab_entries = build_fstab(&fstab_sources, filesystem, &layout, fs_compression);
+    fs::write("/mnt/etc/fstab", render_fstab(&fstab_entries))?;
+    fs::write("/mnt/etc/crypttab", build_crypttab(&fstab_sources))?;
 
     let _ = fs::remove_dir_all("/mnt/root/arch-flux");
     fs::create_dir("/mnt/root/arch-flux")?;
@@ -387,5 +876,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         run_shell_command("arch-chroot /mnt /bin/zsh -c /root/arch-flux/post_chroot")?;
     }
 
+    verify_fstab(&fstab_entries)?;
+    println!("Verified /mnt/etc/fstab against blkid");
+
     Ok(())
 }
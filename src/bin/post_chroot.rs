@@ -1,10 +1,27 @@
-use anyhow::Context;
-use funcs::{find_option, get_march, replace_text, run_command, run_shell_command, touch_file};
+use anyhow::{bail, Context};
+use funcs::bootloader::{
+    configure_ukis, install_efi_fallback, install_grub, install_systemd_boot, install_uki_boot_entries,
+    remove_stale_boot_entries, set_boot_order, uki_loader, GrubTarget, GRUB_LOADER, SYSTEMD_BOOT_LOADER, UKI_DIR,
+};
+use funcs::cmdline::build_cmdline;
+use funcs::filesystem::{Filesystem, Storage};
+use funcs::fstab::{blkid_value, build_root_crypttab, root_luks_options};
+use funcs::initramfs::InitramfsConfig;
+use funcs::kernel::{dkms_packages, Kernels};
+use funcs::layout::{load_layout, Subvolume};
+use funcs::plymouth::configure_plymouth;
+use funcs::secure_boot::configure_secure_boot;
+use funcs::{
+    config_write, fetch_disk, fetch_disk_type, find_disk_option, find_option, get_march, is_bios, partition_path,
+    replace_text, run_command, run_shell_command, touch_file,
+};
 use regex::Regex;
 use std::{
     fs::{self, File},
     io::{BufRead, BufReader, Write},
+    os::unix::fs::{MetadataExt, PermissionsExt},
     path::Path,
+    process::{Command, Stdio},
     thread,
 };
 
@@ -42,7 +59,47 @@ fn main() -> anyhow::Result<()> {
     let username = find_option("username").unwrap();
     let password = find_option("password").unwrap();
     let printers_and_scanners = find_option("printers_and_scanners").unwrap();
-    let wifi_and_bluetooth = find_option("wifi_and_bluetooth").unwrap();
+    let wifi_and_bluetooth = find_option("hardware_wifi_and_bluetooth").unwrap();
+    let tpm2_unlock = find_option("tpm2_unlock").unwrap_or("false".to_string());
+    let tpm2_pcrs = find_option("tpm2_pcrs").unwrap_or("7".to_string());
+    let filesystem = Filesystem::from_options();
+    let storage = Storage::from_disk_options();
+    let kernels = Kernels::from_options();
+    let mut bootloader = find_option("bootloader").unwrap_or("grub".to_string());
+    let mut ukis = find_option("ukis").unwrap_or("false".to_string());
+    let uki_fallback = find_option("uki_fallback").unwrap_or("false".to_string());
+    let mut secure_boot = find_option("secure_boot").unwrap_or("false".to_string());
+    let boot_splash = find_option("boot_splash").unwrap_or("false".to_string());
+    let efi_fallback = find_option("efi_fallback").unwrap_or("true".to_string());
+
+    // systemd-boot, UKIs and Secure Boot all need UEFI.
+    let bios = is_bios();
+    if bios {
+        if bootloader != "grub" || ukis == "true" || secure_boot == "true" {
+            println!("Legacy BIOS boot only supports GRUB, skipping systemd-boot, UKIs and Secure Boot");
+        }
+        bootloader = "grub".to_string();
+        ukis = "false".to_string();
+        secure_boot = "false".to_string();
+    }
+
+    // UKIs are booted by systemd-boot or directly by the firmware, never through GRUB.
+    let grub = bootloader != "systemd-boot" && ukis != "true";
+
+    // snapper relies on Btrfs subvolumes and snapshots.
+    let mut snapshots = find_option("snapshots").unwrap_or("false".to_string());
+    if snapshots == "true" && filesystem != Filesystem::Btrfs {
+        println!("Snapshots need Btrfs, skipping snapper on {}", filesystem.name());
+        snapshots = "false".to_string();
+    }
+
+    // Snapshots are booted from the bootloader's menu, which UKIs booted directly by the firmware don't have.
+    // Under Secure Boot, systemd-stub also ignores the command line that points a UKI to a snapshot.
+    let snapshot_boot =
+        snapshots == "true" && (grub || (bootloader == "systemd-boot" && (ukis != "true" || secure_boot != "true")));
+    if snapshots == "true" && !snapshot_boot {
+        println!("Snapshots can't be booted with this bootloader, UKI and Secure Boot setup; roll back from the ISO");
+    }
 
     run_command(
         "systemd-firstboot",
@@ -82,7 +139,25 @@ fn main() -> anyhow::Result<()> {
     // Safe to do; if say /home/admin existed, it wouldn't also remove /home/admin.
     _ = run_command("userdel", &[&username]);
 
-    let add_user = format!("useradd -m -G users,wheel,video,gamemode -s /bin/zsh {}", &username);
+    // A reinstall keeps the previous home, so reuse its owner's UID and GID instead of the next free ones.
+    let home_owner = fs::metadata(format!("/home/{}", &username))
+        .ok()
+        .map(|metadata| (metadata.uid(), metadata.gid()))
+        .filter(|(uid, _)| *uid != 0);
+    let add_user = match home_owner {
+        Some((uid, gid)) => {
+            create_user_group(&username, gid)?;
+            println!(
+                "Reusing UID {} and GID {} of the existing /home/{}",
+                uid, gid, &username
+            );
+            format!(
+                "useradd -u {} -g {} -G users,wheel,video,gamemode -s /bin/zsh {}",
+                uid, gid, &username
+            )
+        }
+        None => format!("useradd -m -G users,wheel,video,gamemode -s /bin/zsh {}", &username),
+    };
     run_shell_command(&add_user).with_context(|| format!("Failed to create user: {}", &username))?;
 
     run_shell_command(&format!("echo {}:{} | chpasswd", &username, &password))?;
@@ -141,7 +216,7 @@ fn main() -> anyhow::Result<()> {
         ),
         (
             r"\.RUSTFLAGS.*",
-            &format!(r#"RUSTFLAGS="-C opt-level=2 -C target-cpu=native""#),
+            &r#"RUSTFLAGS="-C opt-level=2 -C target-cpu=native""#.to_string(),
         ),
         (
             r"\.MAKEFLAGS.*",
@@ -187,7 +262,7 @@ fn main() -> anyhow::Result<()> {
     let mut packages = Vec::new();
     let mut services = Vec::new();
 
-    if printers_and_scanners == "true".to_string() {
+    if printers_and_scanners == "true" {
         let pac_packages = vec![
             "cups",
             "cups-filters",
@@ -201,16 +276,47 @@ fn main() -> anyhow::Result<()> {
         packages.extend(pac_packages);
     }
 
-    if wifi_and_bluetooth == "true".to_string() {
+    if wifi_and_bluetooth == "true" {
         let wb_packages = vec!["iwd", "bluez", "bluez-utils"];
         packages.extend(wb_packages);
     }
 
+    // tpm2-tss makes sd-encrypt include systemd-cryptsetup's TPM2 token plugin in the initramfs.
+    if tpm2_unlock == "true" {
+        packages.extend(vec!["tpm2-tss"]);
+    }
+
+    // snap-pac takes a pre and post snapshot around every pacman transaction.
+    if snapshots == "true" {
+        packages.extend(vec!["snapper", "snap-pac"]);
+        services.extend(vec!["snapper-timeline.timer", "snapper-cleanup.timer"]);
+
+        // Both regenerate the snapshot boot entries whenever a snapshot is created or deleted.
+        if snapshot_boot && bootloader == "systemd-boot" {
+            services.extend(vec!["snapper-boot-entries.path"]);
+        } else if snapshot_boot {
+            packages.extend(vec!["grub-btrfs", "inotify-tools"]);
+            services.extend(vec!["grub-btrfsd.service"]);
+        }
+    }
+
+    // systemd-boot ships with systemd; systemd-boot-update.service updates it on the ESP after systemd upgrades.
+    if bootloader == "systemd-boot" {
+        services.extend(vec!["systemd-boot-update.service"]);
+    } else if grub {
+        packages.extend(vec!["grub", "os-prober"]);
+    }
+
+    if secure_boot == "true" {
+        packages.extend(vec!["sbctl"]);
+    }
+
+    if boot_splash == "true" {
+        packages.extend(vec!["plymouth"]);
+    }
+
     let default_packages = vec![
         "efibootmgr",
-        "grub",
-        "os-prober",
-        "plymouth",
         "irqbalance",
         "power-profiles-daemon",
         "thermald",
@@ -247,9 +353,16 @@ fn main() -> anyhow::Result<()> {
         "vim",
         "zsh-completions",
     ];
+    if filesystem == Filesystem::Btrfs {
+        services.extend(vec!["btrfs-scrub@-.timer"]);
+    }
+
+    if storage == Storage::Lvm {
+        packages.extend(vec!["lvm2"]);
+    }
+
     let default_services = vec![
         "fstrim.timer",
-        "btrfs-scrub@-.timer",
         "irqbalance.service",
         "dbus-broker.service",
         "power-profiles-daemon.service",
@@ -269,26 +382,24 @@ fn main() -> anyhow::Result<()> {
         // TODO: Test the code inside "none" on a physical PC
         "none" => {
             let reader = BufReader::new(File::open("/proc/cpuinfo")?);
-            for line in reader.lines() {
-                if let Ok(line) = line {
-                    if line.starts_with("vendor") {
-                        let parts: Vec<&str> = line.split(':').collect();
-                        if parts.len() == 1 {
-                            let cpu_vendor: &str = parts[1].trim();
-
-                            match cpu_vendor {
-                                "AuthenticAMD" => {
-                                    println!("AMD CPU detected, adding amd-ucode");
-                                    packages.extend(vec!["amd-ucode"])
-                                }
-                                "GenuineIntel" => {
-                                    println!("Intel CPU detected, adding intel-ucode");
-                                    packages.extend(vec!["intel-ucode"])
-                                }
-                                _ => eprintln!("Your CPU vendor is not supported"),
-                            };
-                            break;
-                        }
+            for line in reader.lines().map_while(Result::ok) {
+                if line.starts_with("vendor") {
+                    let parts: Vec<&str> = line.split(':').collect();
+                    if parts.len() == 2 {
+                        let cpu_vendor: &str = parts[1].trim();
+
+                        match cpu_vendor {
+                            "AuthenticAMD" => {
+                                println!("AMD CPU detected, adding amd-ucode");
+                                packages.extend(vec!["amd-ucode"])
+                            }
+                            "GenuineIntel" => {
+                                println!("Intel CPU detected, adding intel-ucode");
+                                packages.extend(vec!["intel-ucode"])
+                            }
+                            _ => eprintln!("Your CPU vendor is not supported"),
+                        };
+                        break;
                     }
                 }
             }
@@ -322,14 +433,102 @@ fn main() -> anyhow::Result<()> {
         _ => eprintln!("Your virtualization environment is not supported"),
     };
 
+    let dkms = dkms_packages(&find_option("dkms_modules").unwrap_or_default());
+    let kernel_packages = kernels.packages(&dkms);
+    packages.extend(kernel_packages.iter().map(String::as_str));
+    println!("Installing kernels: {}", kernels.boot_order().join(", "));
+
     let package_list = packages.join(" ");
     let pacman_install = format!("pacman -Syuu --quiet --noconfirm --ask=4 --needed {}", &package_list);
     run_shell_command(&pacman_install)?;
 
+    // Deploys unit files, so it has to run before the services are enabled.
+    if snapshots == "true" {
+        configure_snapper(&storage.subvolumes(&load_layout()?))?;
+        if snapshot_boot {
+            configure_snapshot_boot(&bootloader)?;
+        }
+    }
+
     let service_list = services.join(" ");
     let enable_services = format!("systemctl enable {}", &service_list);
     run_shell_command(&enable_services)?;
 
+    if boot_splash == "true" {
+        configure_plymouth(&find_option("plymouth_theme").unwrap_or("bgrt".to_string()))?;
+    }
+
+    let initramfs = InitramfsConfig::from_options(filesystem, storage);
+    initramfs.write()?;
+
+    let tpm2_enrolled = tpm2_unlock == "true" && enroll_tpm2(&tpm2_pcrs)?;
+
+    // The kernels' install hooks built their initramfs before HOOKS and crypttab.initramfs were written.
+    // UKIs are built by configure_ukis() instead.
+    if ukis != "true" {
+        run_command("mkinitcpio", &["-P"]).with_context(|| "Failed to rebuild the initramfs")?;
+        for kernel in &kernels.selected {
+            initramfs.verify(&format!("/boot/initramfs-{}.img", kernel), kernel)?;
+        }
+    }
+
+    let disk = fetch_disk()?;
+    let cmdline = build_cmdline(&disk, filesystem, storage, &load_layout()?, tpm2_enrolled)?;
+    if bios {
+        install_grub(&cmdline, GrubTarget::Bios { disk: &disk }, &kernels.default)?;
+    } else {
+        // Before installing, so leftovers from previous installs don't end up in the BootOrder.
+        remove_stale_boot_entries()?;
+
+        let loaders: Vec<String> = if ukis == "true" {
+            let built = configure_ukis(&cmdline, uki_fallback == "true")?;
+            for kernel in &built {
+                initramfs.verify(&format!("{}/arch-{}.efi", UKI_DIR, kernel), kernel)?;
+            }
+            if bootloader == "systemd-boot" {
+                install_systemd_boot(&cmdline, true, &kernels.default)?;
+                vec![SYSTEMD_BOOT_LOADER.to_string()]
+            } else {
+                let boot_order: Vec<&str> = kernels
+                    .boot_order()
+                    .into_iter()
+                    .filter(|kernel| built.iter().any(|built| built == kernel))
+                    .collect();
+                install_uki_boot_entries(&disk, &boot_order)?;
+                boot_order.into_iter().map(uki_loader).collect()
+            }
+        } else if bootloader == "systemd-boot" {
+            install_systemd_boot(&cmdline, false, &kernels.default)?;
+            vec![SYSTEMD_BOOT_LOADER.to_string()]
+        } else {
+            install_grub(
+                &cmdline,
+                GrubTarget::Efi {
+                    secure_boot: secure_boot == "true",
+                },
+                &kernels.default,
+            )?;
+            vec![GRUB_LOADER.to_string()]
+        };
+
+        // bootctl installs systemd-boot there by itself; GRUB and UKIs booted by the firmware need a copy.
+        if efi_fallback == "true" && bootloader != "systemd-boot" {
+            if let Some(loader) = loaders.first() {
+                install_efi_fallback(loader)?;
+            }
+        }
+        let esp_partuuid = blkid_value(&partition_path(&disk, 1), "PARTUUID")?;
+        set_boot_order(&esp_partuuid, &loaders)?;
+    }
+
+    // Signs what the bootloader setup above put on the ESP.
+    if secure_boot == "true" {
+        configure_secure_boot(
+            find_option("secure_boot_enroll").unwrap_or("true".to_string()) == "true",
+            find_option("secure_boot_microsoft_keys").unwrap_or("true".to_string()) == "true",
+        )?;
+    }
+
     fs::copy(
         "/root/arch-flux/files/etc/X11/Xwrapper.config",
         "/etc/X11/XWrapper.config",
@@ -340,3 +539,157 @@ fn main() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+// "root" for /, otherwise the mountpoint with its slashes replaced, e.g. "home" or "var_lib".
+fn snapper_config_name(mountpoint: &str) -> String {
+    if mountpoint == "/" {
+        "root".to_string()
+    } else {
+        mountpoint.trim_start_matches('/').replace('/', "_")
+    }
+}
+
+// Sets up a snapper config for every subvolume marked for snapshots in the layout.
+// snapper create-config isn't used, since it insists on creating .snapshots itself instead of using @snapshots.
+fn configure_snapper(layout: &[Subvolume]) -> anyhow::Result<()> {
+    let template = fs::read_to_string("/root/arch-flux/files/etc/snapper/configs/root")
+        .with_context(|| "Failed to read the snapper config template")?;
+
+    // Space aware cleanup needs quotas; each config gets its own level 1 qgroup, root's is 1/0.
+    run_command("btrfs", &["quota", "enable", "/"]).with_context(|| "Failed to enable Btrfs quotas")?;
+
+    let mut config_names = Vec::new();
+
+    for (index, subvol) in layout.iter().filter(|subvol| subvol.snapshot).enumerate() {
+        let config_name = snapper_config_name(&subvol.mountpoint);
+        let snapshots_dir = Path::new(&subvol.mountpoint).join(".snapshots");
+
+        // Root uses the dedicated @snapshots subvolume; others get a nested .snapshots subvolume.
+        if !layout
+            .iter()
+            .any(|other| Path::new(&other.mountpoint) == snapshots_dir.as_path())
+            && !snapshots_dir.exists()
+        {
+            run_command("btrfs", &["subvolume", "create", &snapshots_dir.to_string_lossy()])?;
+        }
+        fs::set_permissions(&snapshots_dir, fs::Permissions::from_mode(0o750))?;
+
+        let qgroup = format!("1/{}", index);
+        let _ = run_command("btrfs", &["qgroup", "create", &qgroup, "/"]);
+
+        let config = template
+            .replace("SUBVOLUME=\"/\"", &format!("SUBVOLUME=\"{}\"", subvol.mountpoint))
+            .replace("QGROUP=\"1/0\"", &format!("QGROUP=\"{}\"", qgroup));
+        fs::write(format!("/etc/snapper/configs/{}", config_name), config)?;
+
+        println!("Created snapper config '{}' for {}", config_name, subvol.mountpoint);
+        config_names.push(config_name);
+    }
+
+    touch_file("/etc/conf.d/snapper")?;
+    config_write(
+        &format!("\"{}\"", config_names.join(" ")),
+        "SNAPPER_CONFIGS=",
+        "/etc/conf.d/snapper",
+    )?;
+
+    Ok(())
+}
+
+// Snapshots boot read-only, with systemd.volatile=overlay putting a tmpfs on top so services can still write.
+fn configure_snapshot_boot(bootloader: &str) -> anyhow::Result<()> {
+    if bootloader == "systemd-boot" {
+        let files = [
+            "/usr/local/bin/snapper-boot-entries",
+            "/etc/systemd/system/snapper-boot-entries.path",
+            "/etc/systemd/system/snapper-boot-entries.service",
+        ];
+        for file in files {
+            fs::copy(format!("/root/arch-flux/files{}", file), file)
+                .with_context(|| format!("Failed to copy {}", file))?;
+        }
+        fs::set_permissions("/usr/local/bin/snapper-boot-entries", fs::Permissions::from_mode(0o755))?;
+    } else {
+        config_write(
+            "\"systemd.volatile=overlay\"",
+            "GRUB_BTRFS_SNAPSHOT_KERNEL_PARAMETERS=",
+            "/etc/default/grub-btrfs/config",
+        )
+        .with_context(|| "Failed to configure grub-btrfs")?;
+    }
+
+    Ok(())
+}
+
+// The group's entry from /etc/group, looked up by name or GID; getent exits non-zero if there's none.
+fn getent_group(key: &str) -> Option<String> {
+    let output = Command::new("getent").args(["group", key]).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+// The user's group has to get the GID of the kept /home, or its files end up owned by another group.
+fn create_user_group(username: &str, gid: u32) -> anyhow::Result<()> {
+    let gid_of = |entry: &str| entry.split(':').nth(2).and_then(|gid| gid.parse::<u32>().ok());
+
+    match (getent_group(username), getent_group(&gid.to_string())) {
+        (Some(entry), _) if gid_of(&entry) == Some(gid) => Ok(()),
+        (Some(entry), _) => bail!(
+            "The group {} already exists with GID {}, but /home/{} belongs to GID {}",
+            username,
+            gid_of(&entry).map_or("unknown".to_string(), |gid| gid.to_string()),
+            username,
+            gid
+        ),
+        (None, Some(entry)) => bail!(
+            "GID {} of /home/{} is already taken by the group {}",
+            gid,
+            username,
+            entry.split(':').next().unwrap_or_default()
+        ),
+        (None, None) => {
+            run_command("groupadd", &["-g", &gid.to_string(), username])
+                .with_context(|| format!("Failed to create the group {}", username))?;
+            Ok(())
+        }
+    }
+}
+
+// Adds a TPM2 keyslot next to the passphrase keyslot, which stays as the fallback.
+// The PCRs are measured from the ISO's boot, so only firmware-stable ones (such as 7) survive the first reboot.
+// Returns whether a keyslot was enrolled, so tpm2-device=auto is only set up when there's one to unlock with.
+fn enroll_tpm2(pcrs: &str) -> anyhow::Result<bool> {
+    if !Path::new("/sys/class/tpm/tpm0").exists() {
+        eprintln!("No TPM2 device found, skipping TPM2 enrollment; the passphrase still unlocks the root container");
+        return Ok(false);
+    }
+
+    let disk = fetch_disk()?;
+    let root_part = partition_path(&disk, 3);
+
+    // Prompts for the existing passphrase on the terminal; no password agent runs inside arch-chroot.
+    let status = Command::new("systemd-cryptenroll")
+        .args(["--tpm2-device=auto", &format!("--tpm2-pcrs={}", pcrs), &root_part])
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .status()
+        .with_context(|| "Failed to run systemd-cryptenroll")?;
+    if !status.success() {
+        bail!("Failed to enroll a TPM2 keyslot into {}", root_part);
+    }
+
+    let uuid = blkid_value(&root_part, "UUID")?;
+
+    let options = root_luks_options(
+        &fetch_disk_type(),
+        &find_disk_option("integrity").unwrap_or("none".to_string()),
+        true,
+    );
+    fs::write("/etc/crypttab.initramfs", build_root_crypttab(&uuid, &options))
+        .with_context(|| "Failed to write /etc/crypttab.initramfs")?;
+
+    Ok(true)
+}
@@ -0,0 +1,147 @@
+use anyhow::{bail, Context};
+use funcs::filesystem::{Filesystem, Storage};
+use funcs::layout::{load_layout, root_subvolume};
+use funcs::luks::open_luks_container;
+use funcs::{fetch_disk, prompt, run_command};
+use regex::Regex;
+use std::{
+    env, fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+mod funcs;
+
+// The top-level subvolume (subvolid=5), where @root and @snapshots live side by side.
+const TOP_LEVEL: &str = "/mnt/btrfs-top";
+
+fn usage() -> ! {
+    eprintln!("Usage: rollback <snapshot number>");
+    eprintln!("List the snapshots with: snapper -c root list");
+    std::process::exit(1);
+}
+
+// Booted from a snapshot or the installed system, /dev/mapper/arch is already open; from the ISO it isn't.
+fn open_container() -> anyhow::Result<()> {
+    if Path::new("/dev/mapper/arch").exists() {
+        return Ok(());
+    }
+
+    let disk = fetch_disk().unwrap_or_else(|_| prompt("Disk Arch Flux is installed on, e.g. /dev/nvme0n1: "));
+    open_luks_container(&disk).map_err(|e| anyhow::anyhow!("Failed to open the LUKS2 container: {}", e))?;
+    Ok(())
+}
+
+// A restored snapshot might predate the switch to subvol= entries, so point / at the new root by name.
+fn fix_fstab(root: &Path, root_name: &str) -> anyhow::Result<()> {
+    let fstab_path = root.join("etc/fstab");
+    let contents = fs::read_to_string(&fstab_path).with_context(|| "Failed to read the restored fstab")?;
+
+    let root_line = Regex::new(r"(?m)^(\S+\s+/\s+btrfs\s+\S*?)subvolid=\d+")?;
+    let fixed = root_line.replace_all(&contents, format!("${{1}}subvol=/{}", root_name).as_str());
+
+    if fixed != contents {
+        fs::write(&fstab_path, fixed.as_bytes())?;
+        println!("Replaced the subvolid= reference to / in the restored fstab");
+    }
+    Ok(())
+}
+
+// Only a default subvolume pointing at the old root needs changing; ID 5 (the top level) is left alone.
+fn fix_default_subvolume(old_root_id: &str, new_root: &Path) -> anyhow::Result<()> {
+    let output = run_command("btrfs", &["subvolume", "get-default", TOP_LEVEL])?;
+    let default = String::from_utf8_lossy(&output.stdout);
+
+    if default.split_whitespace().nth(1) == Some(old_root_id) {
+        run_command("btrfs", &["subvolume", "set-default", &new_root.to_string_lossy()])?;
+        println!("Set the default subvolume to the new root");
+    }
+    Ok(())
+}
+
+fn subvolume_id(path: &Path) -> anyhow::Result<String> {
+    let output = run_command("btrfs", &["inspect-internal", "rootid", &path.to_string_lossy()])?;
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn main() -> anyhow::Result<()> {
+    let snapshot = match env::args().nth(1) {
+        Some(snapshot) if snapshot.parse::<u32>().is_ok() => snapshot,
+        _ => usage(),
+    };
+
+    if Filesystem::from_options() != Filesystem::Btrfs {
+        bail!("Rollbacks restore Btrfs snapshots, but the root filesystem isn't Btrfs");
+    }
+
+    // From a fresh ISO boot there's no subvolumes.cfg yet, in which case the default layout is used.
+    fs::create_dir_all("/root/arch-flux")?;
+    let layout = load_layout()?;
+    let root_name = root_subvolume(&layout).name.clone();
+    let snapshots_name = layout
+        .iter()
+        .find(|subvol| subvol.mountpoint == "/.snapshots")
+        .map(|subvol| subvol.name.clone())
+        .unwrap_or("@snapshots".to_string());
+
+    open_container()?;
+    let root_device = Storage::detect()
+        .with_context(|| "Failed to detect the storage layout inside the LUKS2 container")?
+        .root_device();
+
+    fs::create_dir_all(TOP_LEVEL)?;
+    run_command("mount", &["-t", "btrfs", "-o", "subvolid=5", &root_device, TOP_LEVEL])
+        .with_context(|| "Failed to mount the top-level subvolume")?;
+
+    let top = PathBuf::from(TOP_LEVEL);
+    let source = top.join(&snapshots_name).join(&snapshot).join("snapshot");
+    let current_root = top.join(&root_name);
+
+    if !source.is_dir() {
+        let _ = run_command("umount", &[TOP_LEVEL]);
+        bail!("Snapshot {} doesn't exist at {}", snapshot, source.display());
+    }
+
+    let timestamp_output = run_command("date", &["+%Y%m%d-%H%M%S"])?;
+    let timestamp = String::from_utf8_lossy(&timestamp_output.stdout).trim().to_string();
+    let old_root_name = format!("{}.broken-{}", root_name, timestamp);
+    let old_root = top.join(&old_root_name);
+
+    // Renaming works even while the old root is mounted, as mounts refer to the subvolume's ID.
+    let old_root_id = subvolume_id(&current_root)?;
+    fs::rename(&current_root, &old_root).with_context(|| format!("Failed to move {} aside", root_name))?;
+    println!("Moved {} to {}", root_name, old_root_name);
+
+    run_command(
+        "btrfs",
+        &[
+            "subvolume",
+            "snapshot",
+            &source.to_string_lossy(),
+            &current_root.to_string_lossy(),
+        ],
+    )
+    .with_context(|| format!("Failed to create a writable {} from snapshot {}", root_name, snapshot))?;
+    println!("Created a writable {} from snapshot {}", root_name, snapshot);
+
+    fix_default_subvolume(&old_root_id, &current_root)?;
+    fix_fstab(&current_root, &root_name)?;
+
+    // Kept in the top level, so it survives further rollbacks.
+    let record = format!(
+        "{}: rolled {} back to snapshot {}; the previous root is {}, delete it with: \
+        btrfs subvolume delete --recursive {}/{}\n",
+        timestamp, root_name, snapshot, old_root_name, TOP_LEVEL, old_root_name
+    );
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(top.join("arch-flux-rollbacks.log"))?
+        .write_all(record.as_bytes())?;
+    print!("\n{}", record);
+
+    run_command("umount", &[TOP_LEVEL])?;
+    println!("Rollback complete, reboot to use the restored root.");
+
+    Ok(())
+}